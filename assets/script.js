@@ -5,21 +5,39 @@ function copy(event) {
     event.target.blur()
   }
 }
-function remove(slug) {
+function remove(slug, revision) {
   if (typeof slug == 'string') {
     fetch(`${window.location.origin}/s`, {
       method: "DELETE",
       body: JSON.stringify({ "slug": slug }),
-      headers: { "Content-Type": "application/json" }
+      headers: { "Content-Type": "application/json", "If-Match": `"${revision}"` }
     }).then(res => {
+      const form = document.querySelector('form')
       if (res.status < 300) {
         document.getElementById(slug)?.remove()
 
-        const form = document.querySelector('form')
         form.reset()
+        delete form.dataset.revision
+        delete form.dataset.slug
         form.lastElementChild.lastElementChild.children.item(1).innerText = ''
         form.lastElementChild.lastElementChild.children.item(2).disabled = true
         form.classList.add('hidden')
+      } else {
+        res.text().then(message =>
+          form.lastElementChild.lastElementChild.children.item(1).innerText = message
+        )
+      }
+    })
+  }
+}
+function untrash(event) {
+  if (event.isTrusted && event.target == event.currentTarget) {
+    const slug = event.target.getAttribute('data-slug')
+    fetch(`${window.location.origin}/api/shortcuts/${encodeURIComponent(slug)}/restore`, {
+      method: "POST",
+    }).then(res => {
+      if (res.status < 300) {
+        window.location.reload()
       }
     })
   }
@@ -27,12 +45,13 @@ function remove(slug) {
 function edit(event) {
   if (event.isTrusted && event.target == event.currentTarget) {
     event.target.blur()
-    const gate = event.target.parentElement.previousElementSibling
+    const gate = event.target.closest('tr').querySelector('[data-trust]')
     const url = gate.previousElementSibling
-    const slugText = url.previousElementSibling.innerText.trim()
+    const slugText = event.target.closest('tr').id
 
     const form = document.querySelector('form#edit')
     form.querySelector('input[name=slug]').value = slugText
+    form.querySelector('input[name=aliases]').value = url.previousElementSibling.querySelector('[data-aliases]')?.dataset.aliases ?? ''
     form.querySelector('input[name=url]').value = url.innerText.trim()
 
     const from = new Date(+gate.getAttribute('data-since'))
@@ -64,10 +83,17 @@ function edit(event) {
       ('0'+to.getSeconds()).slice(-2)
 
     form.querySelector('input[name=approval]').checked = gate.getAttribute('data-trust') == "untrusted"
+    form.querySelector('select[name=redirect]').value = gate.getAttribute('data-redirect') ?? '0'
+    form.querySelector('select[name=query]').value = gate.getAttribute('data-query') ?? ''
+    // Sent back as If-Match while the slug is kept, so changes made by someone else in the meantime
+    // are not overwritten. A changed slug creates a new shortcut instead.
+    const revision = gate.getAttribute('data-revision')
+    form.dataset.revision = revision
+    form.dataset.slug = slugText
 
     const deleteButton = form.firstElementChild.lastElementChild.lastElementChild
     if (deleteButton instanceof HTMLButtonElement) {
-      deleteButton.addEventListener('click', () => remove(slugText), { once: true })
+      deleteButton.addEventListener('click', () => remove(slugText, revision), { once: true })
       deleteButton.disabled = false
     }
 
@@ -86,6 +112,8 @@ function hide(event) {
   const form = event.currentTarget
   if (event.target == event.currentTarget && event.isTrusted && form instanceof HTMLFormElement) {
     form.reset()
+    delete form.dataset.revision
+    delete form.dataset.slug
 
     form.lastElementChild.lastElementChild.children.item(1).innerText = ''
     form.lastElementChild.lastElementChild.children.item(2).disabled = true
@@ -113,8 +141,8 @@ function put(event) {
   try {
     if (event.isTrusted && event.currentTarget == event.target && event.target instanceof HTMLFormElement) {
       let form = Object.fromEntries(new FormData(event.target))
-      if (form.slug.length == 0 || form.slug.length > 64) {
-        throw new Error('Slug creation: please provide a slug (max. 64).')
+      if (form.slug.length > 64) {
+        throw new Error('Slug creation: please provide a slug of max. 64 characters or leave it empty to generate one.')
       }
       form.url = new URL(form.url).toString()
       form.fromdate = form.fromdate ? form.fromdate.toString() : new Date().toISOString().split("T")[0]
@@ -122,23 +150,37 @@ function put(event) {
       form.todate   = form.todate ? form.todate.toString() : "9999-01-01"
       form.totime   = form.totime ? form.totime.toString() : '00:00'
       form.approval = form.approval == "on"
+      form.aliases = form.aliases.split(/[\s,]+/).filter(alias => alias.length > 0)
+      form.redirect = +form.redirect
+      form.query = form.query || null
 
       const since = new Date(form.fromdate+'T'+form.fromtime).getTime()
       const until = new Date(form.todate+'T'+form.totime).getTime()
+      const headers = { "Content-Type": "application/json" }
+      if (event.target.dataset.revision && form.slug == event.target.dataset.slug) {
+        headers["If-Match"] = `"${event.target.dataset.revision}"`
+      }
 
       fetch(`${window.location.origin}/s`, {
         method: "PUT",
-        headers: { "Content-Type": "application/json" },
+        headers,
         body: JSON.stringify({
           slug: form.slug,
           url: form.url,
           approval: form.approval,
           since,
           until,
+          aliases: form.aliases,
+          redirect: form.redirect,
+          query: form.query,
         }),
       })
-      .then((res) => {
+      .then(async (res) => {
         if (res.status < 300) {
+          const answer = await res.json()
+          // Empty slugs were generated by the server
+          form.slug = answer.slug
+          const changed = (timestamp) => new Date(timestamp).toISOString().slice(0, 16).replace('T', ' ')
           const now = Date.now()
           const gate = now < since ? svgs['countdown'] : now > until ? svgs['blocked'] : svgs['reachable']
           const trust = form.approval ? svgs['untrusted'] : svgs['trusted']
@@ -155,12 +197,20 @@ function put(event) {
             </svg>
           </button>
           <span>${form.slug}</span>
+          ${form.aliases.length == 0 ? '' : `<span class="block text-xs text-zinc-400 truncate" data-aliases="${form.aliases.join(' ')}">
+            ${form.aliases.join(', ')}
+          </span>`}
         </td>
         <td class="px-4 py-2 border border-offblack2 truncate hidden md:table-cell">${form.url}</td>
         <td class="px-4 py-2 border border-offblack2 truncate" data-trust="${form.approval?'untrusted':'trusted'}" data-since="${since}"
-          data-until="${until}">
+          data-until="${until}" data-revision="${answer.revision}" data-redirect="${form.redirect}"
+          data-query="${form.query ?? ''}">
           ${gate} ${trust}
         </td>
+        <td class="px-4 py-2 border border-offblack2 truncate hidden md:table-cell"
+          title="created by ${answer.created_by} on ${changed(answer.created_at)} UTC">
+          ${answer.updated_by}, ${changed(answer.updated_at)}
+        </td>
         <td class="px-4 py-2 border border-offblack2">
           <button
             class="transition-colors hover:text-star-dark focus:outline focus:outline-1 active:outline-none focus:outline-solid"
@@ -180,6 +230,8 @@ function put(event) {
           }
 
           event.target.reset()
+          delete event.target.dataset.revision
+          delete event.target.dataset.slug
           event.target.lastElementChild.lastElementChild.children.item(1).innerText = ''
           event.target.classList.add('hidden')
         } else {
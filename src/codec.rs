@@ -0,0 +1,446 @@
+use crate::database::{QueryPolicy, ShortcutEntry, Trust};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use serde::{Deserialize, Serialize};
+
+/// First line of every file written by Zorka. Files without it are read as the headerless legacy format.
+const MARKER: &str = "# zorka csv v";
+pub const VERSION: u32 = 2;
+const COLUMNS: [&str; 16] = [
+    "slug",
+    "url",
+    "status",
+    "since",
+    "until",
+    "revision",
+    "created_at",
+    "created_by",
+    "updated_at",
+    "updated_by",
+    "deleted_at",
+    "deleted_by",
+    "aliases",
+    "forward_to",
+    "redirect",
+    "query",
+];
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+    Ndjson,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => Some(Self::Csv),
+            "application/json" => Some(Self::Json),
+            "application/x-ndjson" | "application/ndjson" => Some(Self::Ndjson),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Yaml => "application/yaml",
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Yaml => "yaml",
+        }
+    }
+    /// Format of a file by its extension, csv for unknown ones like seed files.
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("json") => Self::Json,
+            Some("ndjson") => Self::Ndjson,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Csv,
+        }
+    }
+}
+
+/// Shortcut as represented in the json and yaml formats, which only carry live shortcuts.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    slug: String,
+    url: String,
+    status: Trust,
+    since: u128,
+    until: u128,
+    #[serde(default = "first_revision")]
+    revision: u64,
+    #[serde(default)]
+    created_at: u128,
+    #[serde(default)]
+    created_by: String,
+    #[serde(default)]
+    updated_at: u128,
+    #[serde(default)]
+    updated_by: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    forward_to: String,
+    #[serde(default)]
+    redirect: u16,
+    #[serde(default)]
+    query: Option<QueryPolicy>,
+}
+
+fn first_revision() -> u64 {
+    1
+}
+
+impl From<&ShortcutEntry> for JsonEntry {
+    fn from(entry: &ShortcutEntry) -> Self {
+        Self {
+            slug: entry.slug.clone(),
+            url: entry.url.clone(),
+            status: entry.trust,
+            since: entry.since,
+            until: entry.until,
+            revision: entry.revision,
+            created_at: entry.created_at,
+            created_by: entry.created_by.clone(),
+            updated_at: entry.updated_at,
+            updated_by: entry.updated_by.clone(),
+            aliases: entry.aliases.clone(),
+            forward_to: entry.forward_to.clone(),
+            redirect: entry.redirect,
+            query: entry.query,
+        }
+    }
+}
+
+impl From<JsonEntry> for ShortcutEntry {
+    fn from(entry: JsonEntry) -> Self {
+        Self {
+            slug: entry.slug,
+            url: entry.url,
+            trust: entry.status,
+            since: entry.since,
+            until: entry.until,
+            revision: entry.revision,
+            created_at: entry.created_at,
+            created_by: entry.created_by,
+            updated_at: entry.updated_at,
+            updated_by: entry.updated_by,
+            deleted_at: 0,
+            deleted_by: String::new(),
+            aliases: entry.aliases,
+            forward_to: entry.forward_to,
+            redirect: entry.redirect,
+            query: entry.query,
+        }
+    }
+}
+
+// Records are parsed and applied one by one, boxing the entry would gain nothing
+#[allow(clippy::large_enum_variant)]
+pub enum Record {
+    Upsert(ShortcutEntry),
+    Delete(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rejection {
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Serializes the entries in the given format, sorted by slug.
+pub fn export(format: Format, entries: &[ShortcutEntry]) -> String {
+    match format {
+        Format::Csv => to_csv(entries),
+        Format::Json => {
+            let entries: Vec<JsonEntry> = sorted(entries).into_iter().map(JsonEntry::from).collect();
+            serde_json::to_string(&entries).expect("could not write json")
+        }
+        Format::Ndjson => sorted(entries)
+            .into_iter()
+            .map(|entry| {
+                serde_json::to_string(&JsonEntry::from(entry)).expect("could not write json") + "\n"
+            })
+            .collect(),
+        Format::Yaml => {
+            let entries: Vec<JsonEntry> = sorted(entries).into_iter().map(JsonEntry::from).collect();
+            serde_yaml::to_string(&entries).expect("could not write yaml")
+        }
+    }
+}
+
+/// Parses entries in the given format, validating them like `parse` does.
+/// Valid entries are passed to `check`, which may adjust them or reject them with a reason.
+pub fn import(
+    format: Format,
+    input: &str,
+    check: &dyn Fn(&mut ShortcutEntry) -> Result<(), String>,
+) -> Vec<Result<Record, Rejection>> {
+    let validate = |line: u64, entry: JsonEntry| {
+        let mut entry = ShortcutEntry::from(entry);
+        entry
+            .validate()
+            .and_then(|_| check(&mut entry))
+            .map(|_| Record::Upsert(entry))
+            .map_err(|reason| Rejection { line, reason })
+    };
+    match format {
+        Format::Csv => parse_checked(input, false, check),
+        Format::Json => match serde_json::from_str::<Vec<JsonEntry>>(input) {
+            // Json arrays have no lines, the position in the array is reported instead
+            Ok(entries) => entries
+                .into_iter()
+                .enumerate()
+                .map(|(index, entry)| validate(index as u64 + 1, entry))
+                .collect(),
+            Err(e) => vec![Err(Rejection {
+                line: e.line() as u64,
+                reason: e.to_string(),
+            })],
+        },
+        Format::Yaml => match serde_yaml::from_str::<Vec<JsonEntry>>(input) {
+            // Like json arrays the position in the sequence is reported
+            Ok(entries) => entries
+                .into_iter()
+                .enumerate()
+                .map(|(index, entry)| validate(index as u64 + 1, entry))
+                .collect(),
+            Err(e) => vec![Err(Rejection {
+                line: e.location().map_or(0, |location| location.line() as u64),
+                reason: e.to_string(),
+            })],
+        },
+        Format::Ndjson => input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| match serde_json::from_str::<JsonEntry>(line) {
+                Ok(entry) => validate(index as u64 + 1, entry),
+                Err(e) => Err(Rejection {
+                    line: index as u64 + 1,
+                    reason: e.to_string(),
+                }),
+            })
+            .collect(),
+    }
+}
+
+/// Serializes the entries as a versioned RFC 4180 csv with a header row, sorted by slug.
+pub fn to_csv(entries: &[ShortcutEntry]) -> String {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    writer.write_record(COLUMNS).expect("could not write csv");
+    for entry in sorted(entries) {
+        writer.write_record(fields(entry)).expect("could not write csv");
+    }
+    format!("{MARKER}{VERSION}\n{}", finish(writer))
+}
+
+/// Header of a new journal, the csv columns prefixed by the operation.
+pub fn journal_header() -> String {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    let mut columns = vec!["op"];
+    columns.extend(COLUMNS);
+    writer.write_record(columns).expect("could not write csv");
+    format!("{MARKER}{VERSION}\n{}", finish(writer))
+}
+
+/// Serializes a mutation as a single journal record.
+pub fn journal_record(record: &Record) -> String {
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(vec![]);
+    match record {
+        Record::Upsert(entry) => {
+            let mut row = vec!["upsert".to_string()];
+            row.extend(fields(entry));
+            writer.write_record(row)
+        }
+        Record::Delete(slug) => writer.write_record(["delete", slug.as_str()]),
+    }
+    .expect("could not write csv");
+    finish(writer)
+}
+
+/// Parses a csv file or journal. Every record is validated the same way `PUT /s` validates,
+/// rejected records carry their line number and the reason.
+pub fn parse(input: &str, journal: bool) -> Vec<Result<Record, Rejection>> {
+    parse_checked(input, journal, &|_| Ok(()))
+}
+
+fn parse_checked(
+    input: &str,
+    journal: bool,
+    check: &dyn Fn(&mut ShortcutEntry) -> Result<(), String>,
+) -> Vec<Result<Record, Rejection>> {
+    let (version, body, offset) = match input.strip_prefix(MARKER) {
+        Some(rest) => {
+            let (version, body) = rest.split_once('\n').unwrap_or((rest, ""));
+            match version.trim().parse::<u32>() {
+                Ok(version) if version <= VERSION => (version, body, 1),
+                _ => {
+                    return vec![Err(Rejection {
+                        line: 1,
+                        reason: format!("unsupported format version '{}'", version.trim()),
+                    })]
+                }
+            }
+        }
+        None => (1, input, 0),
+    };
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(version > 1)
+        .flexible(true)
+        .from_reader(body.as_bytes());
+    // Legacy files have no header and a fixed column order
+    let mut names: Vec<String> = COLUMNS.iter().map(|column| column.to_string()).collect();
+    if journal {
+        names.insert(0, "op".into());
+    }
+    if version > 1 {
+        match reader.headers() {
+            Ok(headers) => names = headers.iter().map(|name| name.trim().to_string()).collect(),
+            Err(e) => {
+                return vec![Err(Rejection {
+                    line: offset + 1,
+                    reason: e.to_string(),
+                })]
+            }
+        }
+    }
+
+    let mut records = vec![];
+    for result in reader.records() {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                records.push(Err(Rejection {
+                    line: line + offset,
+                    reason: e.to_string(),
+                }));
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line()) + offset;
+        if record.iter().all(|field| field.is_empty()) {
+            continue;
+        }
+        let record = to_record(&names, &record, journal).and_then(|record| match record {
+            Record::Upsert(mut entry) => check(&mut entry).map(|_| Record::Upsert(entry)),
+            delete => Ok(delete),
+        });
+        records.push(record.map_err(|reason| Rejection { line, reason }));
+    }
+    records
+}
+
+fn to_record(names: &[String], record: &StringRecord, journal: bool) -> Result<Record, String> {
+    let field = |name: &str| -> Option<&str> {
+        names
+            .iter()
+            .position(|column| column == name)
+            .and_then(|index| record.get(index))
+    };
+    let slug = field("slug").ok_or("missing slug")?.to_string();
+    let op = if journal { field("op") } else { Some("upsert") };
+    match op {
+        Some("delete") => Ok(Record::Delete(slug)),
+        Some("upsert") => {
+            let entry = ShortcutEntry {
+                slug,
+                url: field("url").ok_or("missing url")?.to_string(),
+                trust: field("status").ok_or("missing status")?.parse()?,
+                since: timestamp(field("since").ok_or("missing since")?)?,
+                until: timestamp(field("until").ok_or("missing until")?)?,
+                // Files written before revisions existed lack the column
+                revision: match field("revision") {
+                    Some(value) if !value.is_empty() => value
+                        .parse()
+                        .map_err(|_| format!("'{value}' is not a revision."))?,
+                    _ => first_revision(),
+                },
+                created_at: optional_timestamp(field("created_at"))?,
+                created_by: field("created_by").unwrap_or_default().to_string(),
+                updated_at: optional_timestamp(field("updated_at"))?,
+                updated_by: field("updated_by").unwrap_or_default().to_string(),
+                deleted_at: optional_timestamp(field("deleted_at"))?,
+                deleted_by: field("deleted_by").unwrap_or_default().to_string(),
+                aliases: field("aliases")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+                forward_to: field("forward_to").unwrap_or_default().to_string(),
+                redirect: match field("redirect") {
+                    Some(value) if !value.is_empty() => value
+                        .parse()
+                        .map_err(|_| format!("'{value}' is not a redirect status."))?,
+                    _ => 0,
+                },
+                query: match field("query") {
+                    Some(value) if !value.is_empty() => Some(value.parse()?),
+                    _ => None,
+                },
+            };
+            entry.validate()?;
+            Ok(Record::Upsert(entry))
+        }
+        _ => Err("unknown journal operation".into()),
+    }
+}
+
+fn timestamp(value: &str) -> Result<u128, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{value}' is not UNIX time in milliseconds."))
+}
+
+/// Timestamps of columns added after the first version, 0 if missing.
+fn optional_timestamp(value: Option<&str>) -> Result<u128, String> {
+    match value {
+        Some(value) if !value.is_empty() => timestamp(value),
+        _ => Ok(0),
+    }
+}
+
+fn sorted(entries: &[ShortcutEntry]) -> Vec<&ShortcutEntry> {
+    let mut entries: Vec<&ShortcutEntry> = entries.iter().collect();
+    entries.sort_by(|a, b| a.slug.cmp(&b.slug));
+    entries
+}
+
+fn fields(entry: &ShortcutEntry) -> Vec<String> {
+    vec![
+        entry.slug.clone(),
+        entry.url.clone(),
+        entry.trust.to_string(),
+        entry.since.to_string(),
+        entry.until.to_string(),
+        entry.revision.to_string(),
+        entry.created_at.to_string(),
+        entry.created_by.clone(),
+        entry.updated_at.to_string(),
+        entry.updated_by.clone(),
+        entry.deleted_at.to_string(),
+        entry.deleted_by.clone(),
+        entry.aliases.join(" "),
+        entry.forward_to.clone(),
+        entry.redirect.to_string(),
+        entry.query.map(|query| query.to_string()).unwrap_or_default(),
+    ]
+}
+
+fn finish(writer: csv::Writer<Vec<u8>>) -> String {
+    String::from_utf8(writer.into_inner().expect("could not write csv")).expect("csv is not utf-8")
+}
@@ -8,11 +8,31 @@ use base64::{
 use regex::Regex;
 use serde::Deserialize;
 
+use crate::{
+    database::{QueryPolicy, REDIRECTS},
+    seal, slugs,
+};
+
 #[derive(Clone, Deserialize, Default)]
 pub struct Configuration {
     pub auth: Authentication,
     pub i18n: Internationalization,
     pub server: ServerInformation,
+    #[serde(default)]
+    pub storage: StorageEngine,
+    #[serde(default)]
+    pub backups: Backups,
+    #[serde(default)]
+    pub trash: Trash,
+    #[serde(default)]
+    pub replication: Replication,
+    #[serde(default)]
+    pub slugs: Slugs,
+    #[serde(default)]
+    pub redirects: Redirects,
+    /// Seed file the shortcuts are continuously reconciled to. Disabled if not set.
+    #[serde(default)]
+    pub gitops: Option<GitOps>,
 }
 
 #[derive(Clone, Deserialize, Default)]
@@ -38,6 +58,193 @@ pub enum Authentication {
     },
 }
 
+#[derive(Clone, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEngine {
+    /// Held in memory, persisted as csv backups and a journal
+    #[default]
+    Memory,
+    /// Embedded SQLite database file
+    Sqlite { path: String },
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Backups {
+    pub directory: String,
+    /// Number of backups to keep, the newest one is always kept.
+    pub retention_count: usize,
+    /// Seconds after which a backup is removed. Kept forever if not set.
+    pub retention_age: Option<u64>,
+    /// Name of the backup to restore from instead of the newest valid one.
+    pub restore_from: Option<String>,
+    /// Encrypts backups, the journal and `/store` exports. Unencrypted backups only carry a checksum.
+    pub encryption: Option<Encryption>,
+    /// Refuses to start if a row of the seed file or a backup is rejected, instead of skipping it.
+    pub strict: bool,
+    /// Restores backups and the journal written before the key was configured, for one start
+    /// after configuring it. With a key they are refused otherwise, as they could have been planted.
+    pub migrate_unencrypted: bool,
+    /// Number of revisions kept in the history of every shortcut, the latest one is always kept.
+    pub history_limit: usize,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    /// Base64 encoded 256 bit key
+    Key(String),
+    /// File containing the base64 encoded key, replaced by `Key` when the configuration is loaded
+    KeyFile(String),
+}
+
+impl Default for Backups {
+    fn default() -> Self {
+        Self {
+            directory: "./backups".into(),
+            retention_count: 5,
+            retention_age: None,
+            restore_from: None,
+            encryption: None,
+            strict: false,
+            migrate_unencrypted: false,
+            history_limit: 100,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Trash {
+    /// Seconds after which deleted shortcuts are purged from the trash.
+    pub purge_after: u64,
+}
+
+impl Default for Trash {
+    fn default() -> Self {
+        Self {
+            purge_after: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Redirects {
+    /// Status shortcuts without their own redirect with, one of 301, 302, 303, 307 or 308.
+    pub status: u16,
+    /// What shortcuts without their own policy do with the query string of a visit.
+    pub query: QueryPolicy,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Self {
+            status: 303,
+            query: QueryPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Replication {
+    /// Base URLs of the instances to replicate with. Replication is disabled without peers.
+    pub peers: Vec<String>,
+    /// Secret shared by all peers, presented as bearer token when pulling.
+    pub secret: String,
+    /// Seconds between pulls from every peer.
+    pub interval: u64,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            peers: vec![],
+            secret: String::new(),
+            interval: 5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct Slugs {
+    /// Slugs generated for shortcuts created without one
+    pub generate: SlugGenerator,
+    /// Slugs that cannot be created, compared case-insensitively
+    pub reserved: Vec<String>,
+    /// Regular expressions of slugs that cannot be created, matching the whole slug
+    pub reserved_patterns: Vec<String>,
+    /// Words no slug may contain, compared case-insensitively
+    pub blocked: Vec<String>,
+    /// Files with one blocked word per line, added to `blocked` when the configuration is loaded
+    pub blocked_files: Vec<String>,
+    /// Identities allowed to create reserved and blocked slugs anyway
+    pub override_by: Vec<String>,
+    /// How slugs are normalized before they are stored or looked up
+    pub normalize: Normalization,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Normalization {
+    /// Strips leading and trailing whitespace
+    pub trim: bool,
+    /// Composes Unicode characters (NFC), so a slug typed with combining marks matches the composed one
+    pub nfc: bool,
+    /// Matches slugs case-insensitively by storing them in lowercase
+    pub fold_case: bool,
+    /// Rejects slugs and aliases that look like those of another shortcut, like a Cyrillic `а` for `a`
+    pub reject_confusables: bool,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            trim: true,
+            nfc: true,
+            fold_case: false,
+            reject_confusables: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SlugGenerator {
+    pub alphabet: String,
+    pub length: usize,
+    /// Leaves out characters like `0` and `O` or `1` and `l` that are easily confused
+    pub avoid_ambiguous: bool,
+}
+
+impl Default for SlugGenerator {
+    fn default() -> Self {
+        Self {
+            alphabet: "abcdefghijklmnopqrstuvwxyz0123456789".into(),
+            length: 6,
+            avoid_ambiguous: true,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct GitOps {
+    /// Seed file in any import format, picked by its extension and csv by default
+    pub path: String,
+    /// Seconds between reconciliations
+    #[serde(default = "reconcile_interval")]
+    pub interval: u64,
+    /// Moves shortcuts missing in the file to the trash instead of only reporting them as drift
+    #[serde(default)]
+    pub prune: bool,
+}
+
+fn reconcile_interval() -> u64 {
+    10
+}
+
 #[derive(Clone, Deserialize, Default)]
 pub struct Internationalization {
     pub lang: String,
@@ -56,6 +263,24 @@ pub struct Untrusted {
 #[derive(Clone, Deserialize, Default)]
 pub struct ServerInformation {
     pub public_origin: String,
+    /// Seconds between automatic snapshots of the database. Disabled if not set.
+    #[serde(default)]
+    pub snapshot_interval: Option<u64>,
+    #[serde(default)]
+    pub mode: ServerMode,
+    /// Base URL of the instance a follower mirrors
+    #[serde(default)]
+    pub primary: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    /// Shortcuts are edited on this instance
+    #[default]
+    Primary,
+    /// Read-only mirror of the primary, pulled with the replication secret
+    Follower,
 }
 
 #[derive(Deserialize)]
@@ -67,8 +292,14 @@ pub async fn handle_authorization(
     config: &Configuration,
     headermap: &HeaderMap,
 ) -> Option<HttpResponse> {
+    authorize(config, headermap).await.err()
+}
+
+/// Authorizes the request like `handle_authorization` and returns the identity changes are attributed to:
+/// the basic auth username, the OAuth2 subject or `anonymous` without authentication.
+pub async fn authorize(config: &Configuration, headermap: &HeaderMap) -> Result<String, HttpResponse> {
     match &config.auth {
-        Authentication::None => None,
+        Authentication::None => Ok("anonymous".into()),
         Authentication::OAuth2 {
             client_id,
             scope,
@@ -80,7 +311,7 @@ pub async fn handle_authorization(
             if let Some(value) = headermap.get("Cookie") {
                 let token = &value.to_str().unwrap().to_string()[6..];
                 if Regex::new(r"^[a-zA-Z0-9-._~]+$").unwrap().is_match(token) {
-                    if let Ok(_response) = Command::new("/usr/bin/curl")
+                    if let Ok(response) = Command::new("/usr/bin/curl")
                         .arg("-XPOST")
                         .arg("--fail")
                         .args(["-H", "Content-Length: 0"])
@@ -89,12 +320,14 @@ pub async fn handle_authorization(
                         .arg(introspect_url)
                         .output()
                     {
-                        return None;
+                        if response.status.success() {
+                            return Ok(subject(&response.stdout));
+                        }
                     }
                 }
             }
 
-            Some(
+            Err(
                 HttpResponse::SeeOther()
                     .insert_header((
                         "Location",
@@ -105,11 +338,12 @@ pub async fn handle_authorization(
         }
         Authentication::BasicPrerendered { header } => {
             if let Some(val) = headermap.get("Authorization") {
-                if header == val.to_str().unwrap_or_default() {
-                    return None;
+                let val = val.to_str().unwrap_or_default();
+                if header == val {
+                    return Ok(username(val));
                 }
             }
-            Some(
+            Err(
                 HttpResponse::Unauthorized()
                     .insert_header(("WWW-Authenticate", "Basic realm=\"Zorka\""))
                     .finish(),
@@ -119,56 +353,85 @@ pub async fn handle_authorization(
     }
 }
 
+/// Username of a basic authorization header.
+fn username(header: &str) -> String {
+    let credentials = header.trim_start_matches("Basic ");
+    general_purpose::STANDARD
+        .decode(credentials)
+        .or_else(|_| general_purpose::URL_SAFE.decode(credentials))
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| decoded.split_once(':').map(|(name, _)| name.to_string()))
+        .unwrap_or_default()
+}
+
+/// Subject of an introspection or userinfo answer. Providers without `sub` like GitHub are identified by their login.
+fn subject(answer: &[u8]) -> String {
+    let answer: serde_json::Value = serde_json::from_slice(answer).unwrap_or_default();
+    ["sub", "login", "id"]
+        .iter()
+        .find_map(|key| match answer.get(key) {
+            Some(serde_json::Value::String(value)) => Some(value.clone()),
+            Some(serde_json::Value::Number(value)) => Some(value.to_string()),
+            _ => None,
+        })
+        .unwrap_or_else(|| "unknown".into())
+}
+
 pub fn get_config() -> Configuration {
     let filereader = std::fs::File::open("./configuration.yaml").expect("missing configuration!");
-    let config: Configuration =
+    let mut config: Configuration =
         serde_yaml::from_reader(&filereader).expect("unparsable configuration!");
 
-    match config.auth {
-        Authentication::None => Configuration {
-            auth: Authentication::None,
-            i18n: config.i18n,
-            server: config.server,
-        },
-        Authentication::Basic { username, password } => {
-            // Prerender Basic Auth header and just compare at runtime
-            const ENGINE: GeneralPurpose =
-                GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::PAD);
-            let b64 = ENGINE.encode(format!("{username}:{password}"));
-
-            Configuration {
-                auth: Authentication::BasicPrerendered { header: format!("Basic {b64}") },
-                i18n: config.i18n,
-                server: config.server,
-            }
-        }
-        Authentication::BasicPrerendered { header } => {
-            Configuration {
-                auth: Authentication::BasicPrerendered { header },
-                i18n: config.i18n,
-                server: config.server,
-            }
-        }
-        Authentication::OAuth2 {
-            client_id,
-            client_secret,
-            scope,
-            auth_url,
-            token_url,
-            redirect_url,
-            introspect_url,
-        } => Configuration {
-            auth: Authentication::OAuth2 {
-                client_id,
-                client_secret,
-                scope,
-                auth_url,
-                token_url,
-                redirect_url,
-                introspect_url,
-            },
-            i18n: config.i18n,
-            server: config.server,
-        },
+    if let Authentication::Basic { username, password } = &config.auth {
+        // Prerender Basic Auth header and just compare at runtime
+        const ENGINE: GeneralPurpose =
+            GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::PAD);
+        let b64 = ENGINE.encode(format!("{username}:{password}"));
+        config.auth = Authentication::BasicPrerendered {
+            header: format!("Basic {b64}"),
+        };
+    }
+    if let Some(Encryption::KeyFile(path)) = &config.backups.encryption {
+        let key = std::fs::read_to_string(path).expect("unreadable backup key file!");
+        config.backups.encryption = Some(Encryption::Key(key.trim().to_string()));
+    }
+    if let Some(encryption) = &config.backups.encryption {
+        seal::cipher(encryption).expect("invalid backup key!");
+    }
+    if config.server.mode == ServerMode::Follower {
+        let primary = config
+            .server
+            .primary
+            .clone()
+            .expect("a follower requires the url of its primary!");
+        // A follower only pulls from its primary
+        config.replication.peers = vec![primary];
+    }
+    for path in std::mem::take(&mut config.slugs.blocked_files) {
+        let words = std::fs::read_to_string(&path).expect("unreadable blocked slugs file!");
+        config.slugs.blocked.extend(
+            words
+                .lines()
+                .map(|word| word.trim().to_string())
+                .filter(|word| !word.is_empty() && !word.starts_with('#')),
+        );
+    }
+    for pattern in &config.slugs.reserved_patterns {
+        Regex::new(pattern).expect("invalid reserved slug pattern!");
+    }
+    let generator = &config.slugs.generate;
+    if slugs::alphabet(generator).is_empty() || !(1..=64).contains(&generator.length) {
+        panic!("generated slugs need an alphabet and a length of 1 to 64!");
+    }
+    if !REDIRECTS.contains(&config.redirects.status) {
+        panic!("redirects.status must be 301, 302, 303, 307 or 308!");
+    }
+    if config.server.mode == ServerMode::Follower && config.gitops.is_some() {
+        panic!("a follower mirrors its primary and cannot reconcile a seed file!");
+    }
+    if !config.replication.peers.is_empty() && config.replication.secret.is_empty() {
+        panic!("replication requires a secret!");
     }
+    config
 }
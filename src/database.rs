@@ -1,52 +1,368 @@
+use crate::codec::{self, Record, Rejection};
+use crate::configuration::{Backups, Encryption};
+use crate::seal::{self, JournalSeal};
+use crate::slugs;
+use crate::storage::{
+    backup_files, prune_backups, write_snapshot, Storage, WriteError, HISTORY, JOURNAL,
+};
 use regex::Regex;
-use std::fs::{metadata, remove_file};
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use std::fs::{metadata, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
 use std::{collections::HashMap, sync::Arc};
-use std::{
-    fs,
-    io::{BufRead, BufReader},
-};
+use std::fmt::{self, Display};
+use std::fs;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-#[derive(Clone)]
+/// Redirect statuses a shortcut can answer with, permanent and temporary, keeping the method or not.
+pub const REDIRECTS: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// Whether visitors are redirected right away or have to approve the target first.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+    Trusted,
+    Untrusted,
+}
+
+impl Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trust::Trusted => write!(f, "trusted"),
+            Trust::Untrusted => write!(f, "untrusted"),
+        }
+    }
+}
+
+impl FromStr for Trust {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "trusted" => Ok(Trust::Trusted),
+            "untrusted" => Ok(Trust::Untrusted),
+            _ => Err("The status must be 'trusted' or 'untrusted'.".into()),
+        }
+    }
+}
+
+/// What happens to the query string of a visit, like `?week=42` of `/s/report?week=42`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryPolicy {
+    /// The target is visited as it is
+    #[default]
+    Drop,
+    /// The parameters are added after those of the target
+    Append,
+    /// The parameters replace those of the target with the same name and are added otherwise
+    Override,
+}
+
+impl QueryPolicy {
+    /// Merges the raw query string of a visit into the target URL, keeping its fragment last.
+    pub fn apply(&self, url: &str, query: &str) -> String {
+        let visit: Vec<&str> = query.split('&').filter(|pair| !pair.is_empty()).collect();
+        if *self == QueryPolicy::Drop || visit.is_empty() {
+            return url.into();
+        }
+        let (rest, fragment) = match url.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (url, None),
+        };
+        let (base, target) = rest.split_once('?').unwrap_or((rest, ""));
+        let name = |pair: &&str| pair.split('=').next().unwrap_or_default().to_string();
+        let mut pairs: Vec<&str> = target.split('&').filter(|pair| !pair.is_empty()).collect();
+        if *self == QueryPolicy::Override {
+            let names: Vec<String> = visit.iter().map(name).collect();
+            pairs.retain(|pair| !names.contains(&name(pair)));
+        }
+        pairs.extend(visit);
+
+        let mut merged = format!("{base}?{}", pairs.join("&"));
+        if let Some(fragment) = fragment {
+            merged.push('#');
+            merged.push_str(fragment);
+        }
+        merged
+    }
+}
+
+impl Display for QueryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryPolicy::Drop => write!(f, "drop"),
+            QueryPolicy::Append => write!(f, "append"),
+            QueryPolicy::Override => write!(f, "override"),
+        }
+    }
+}
+
+impl FromStr for QueryPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(QueryPolicy::Drop),
+            "append" => Ok(QueryPolicy::Append),
+            "override" => Ok(QueryPolicy::Override),
+            _ => Err("The query policy must be 'drop', 'append' or 'override'.".into()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct ShortcutEntry {
     pub slug: String,
     pub url: String,
-    pub status: String,
-    pub since: String,
-    pub until: String,
+    pub trust: Trust,
+    /// Start of the availability window in milliseconds since the UNIX epoch
+    pub since: u128,
+    /// End of the availability window in milliseconds since the UNIX epoch
+    pub until: u128,
+    /// Incremented by the storage on every write, starting at 1
+    pub revision: u64,
+    /// Milliseconds since the UNIX epoch, 0 if unknown
+    pub created_at: u128,
+    /// Identity that created the shortcut, empty if unknown
+    pub created_by: String,
+    /// Milliseconds since the UNIX epoch, 0 if unknown
+    pub updated_at: u128,
+    /// Identity that last changed the shortcut, empty if unknown
+    pub updated_by: String,
+    /// Milliseconds since the UNIX epoch the shortcut was moved to the trash, 0 if it is live
+    pub deleted_at: u128,
+    /// Identity that moved the shortcut to the trash
+    pub deleted_by: String,
+    /// Further slugs redirecting like this one while it is live
+    pub aliases: Vec<String>,
+    /// Slug this one was renamed to and forwards to during its window, empty for regular shortcuts
+    pub forward_to: String,
+    /// HTTP status visitors are redirected with, 0 for the configured default
+    pub redirect: u16,
+    /// How the query string of a visit reaches the target, `None` for the configured default
+    pub query: Option<QueryPolicy>,
+}
+
+impl ShortcutEntry {
+    /// New live entry without any history.
+    pub fn new(slug: String, url: String, trust: Trust, since: u128, until: u128) -> Self {
+        Self {
+            slug,
+            url,
+            trust,
+            since,
+            until,
+            revision: 0,
+            created_at: 0,
+            created_by: String::new(),
+            updated_at: 0,
+            updated_by: String::new(),
+            deleted_at: 0,
+            deleted_by: String::new(),
+            aliases: vec![],
+            forward_to: String::new(),
+            redirect: 0,
+            query: None,
+        }
+    }
+
+    /// Validates the entry, the same rules apply to `PUT /s` and every restored row.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.slug.len() > 64 || self.slug.is_empty() {
+            return Err("Provide a non empty slug (max. 64).".into());
+        }
+        let regex =
+            Regex::new(r"^https?://(www\.)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b([-a-zA-Z0-9()!@:%_\+.~#?&//=]*)$")
+            .expect("invalid url regex");
+        if regex.captures(&self.url).is_none() {
+            return Err("The provided URL is invalid.".into());
+        }
+        // Keeps the window portable between all storage engines
+        if self.since > i64::MAX as u128 || self.until > i64::MAX as u128 {
+            return Err("The availability window is out of range.".into());
+        }
+        // Aliases are stored separated by spaces
+        for (index, alias) in self.aliases.iter().enumerate() {
+            if alias.is_empty() || alias.len() > 64 || alias.contains(char::is_whitespace) {
+                return Err("Provide aliases without whitespace (max. 64).".into());
+            }
+            if *alias == self.slug || self.aliases[..index].contains(alias) {
+                return Err(format!("The alias '{alias}' is given twice."));
+            }
+        }
+        if self.forward_to.len() > 64 || self.forward_to == self.slug {
+            return Err("Provide a different slug to forward to (max. 64).".into());
+        }
+        if self.redirect != 0 && !REDIRECTS.contains(&self.redirect) {
+            return Err("The redirect status must be 301, 302, 303, 307 or 308.".into());
+        }
+        if [self.created_at, self.updated_at, self.deleted_at]
+            .iter()
+            .any(|timestamp| *timestamp > i64::MAX as u128)
+        {
+            return Err("The change timestamps are out of range.".into());
+        }
+        Ok(())
+    }
+
+    /// Attributes a change to the author. Entries without a known creator are attributed as created by them.
+    /// The storage keeps the creation of an existing entry on upsert.
+    pub fn stamp(&mut self, author: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_millis();
+        if self.created_by.is_empty() {
+            self.created_at = now;
+            self.created_by = author.into();
+        }
+        self.updated_at = now;
+        self.updated_by = author.into();
+    }
+
+    /// Moves the entry to the trash, attributed to the author.
+    pub fn trash(&mut self, author: &str) {
+        self.stamp(author);
+        self.deleted_at = self.updated_at;
+        self.deleted_by = author.into();
+    }
+
+    /// Restores the entry from the trash, attributed to the author.
+    pub fn untrash(&mut self, author: &str) {
+        self.stamp(author);
+        self.deleted_at = 0;
+        self.deleted_by = String::new();
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        self.deleted_at > 0
+    }
+
+    /// Whether the entry wins over another version of it replicated from a peer: the higher revision,
+    /// then the later change. The remaining fields only break ties, so every peer picks the same version.
+    pub fn supersedes(&self, other: &ShortcutEntry) -> bool {
+        let precedence = |entry: &ShortcutEntry| {
+            (
+                entry.revision,
+                entry.updated_at,
+                entry.deleted_at,
+                entry.updated_by.clone(),
+                entry.url.clone(),
+                entry.trust.to_string(),
+                entry.since,
+                entry.until,
+                entry.aliases.clone(),
+                entry.forward_to.clone(),
+                entry.redirect,
+                entry.query.map(|query| query.to_string()),
+            )
+        };
+        precedence(self) > precedence(other)
+    }
+
+    /// Compares what visitors get from the entries, ignoring the revision and other metadata.
+    pub fn same_target(&self, other: &ShortcutEntry) -> bool {
+        self.slug == other.slug
+            && self.url == other.url
+            && self.trust == other.trust
+            && self.since == other.since
+            && self.until == other.until
+            && self.aliases == other.aliases
+            && self.forward_to == other.forward_to
+            && self.redirect == other.redirect
+            && self.query == other.query
+    }
+
+    /// Whether the slug was renamed and this entry only forwards to the new one.
+    pub fn is_tombstone(&self) -> bool {
+        !self.forward_to.is_empty()
+    }
+
+    /// Whether the entry redirects under the slug, as its own or as an alias.
+    pub fn answers(&self, slug: &str) -> bool {
+        self.names().any(|name| name == slug)
+    }
+
+    /// The slug followed by the aliases.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.slug).chain(self.aliases.iter())
+    }
 }
 
+/// In-memory storage, backed by csv snapshots and a journal of the mutations since.
 pub struct Database {
-    backup: bool,
+    backups: Option<Backups>,
     instance_id: String,
     data: Arc<RwLock<HashMap<String, ShortcutEntry>>>,
+    journal: Mutex<Option<Log>>,
+    history: Mutex<History>,
+    /// Whether there are mutations since the last snapshot
+    dirty: AtomicBool,
 }
 
 impl Database {
-    pub fn new(backup: bool) -> Self {
+    /// Opens the database. With backups every mutation is journaled into
+    /// the backup directory before being applied, without them the database is ephemeral.
+    pub fn new(backups: Option<&Backups>) -> Self {
+        let instance_id = Uuid::new_v4().to_string();
+        let (data, journal, history) = match backups {
+            Some(backups) => {
+                let data = restore_data(backups);
+                // Nothing is pruned until the instance is up, a failing boot must not rotate out good backups
+                let journal =
+                    compact(backups, &instance_id, &data, false).map(|(_, journal)| journal);
+                let history = History::open(backups, &data);
+                (data, journal, history)
+            }
+            None => (HashMap::new(), None, History::new(Backups::default().history_limit)),
+        };
         Self {
-            backup,
-            data: Arc::new(RwLock::new(restore_data())),
-            instance_id: Uuid::new_v4().to_string(),
+            backups: backups.cloned(),
+            data: Arc::new(RwLock::new(data)),
+            instance_id,
+            journal: Mutex::new(journal),
+            history: Mutex::new(history),
+            dirty: AtomicBool::new(false),
         }
     }
-    pub fn read(&self, slug: &String) -> Option<ShortcutEntry> {
+
+    /// Appends a mutation to the journal and syncs it to disk.
+    /// Must be called while holding the data write lock to keep the journal ordered.
+    fn append(&self, line: &str) -> bool {
+        match self.journal.lock() {
+            Ok(mut journal) => match journal.as_mut() {
+                Some(log) => match log.append(line) {
+                    Ok(_) => {
+                        self.dirty.store(true, Ordering::SeqCst);
+                        true
+                    }
+                    Err(e) => {
+                        println!("Could not write to the journal: {e}");
+                        false
+                    }
+                },
+                None => self.backups.is_none(),
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+impl Storage for Database {
+    fn read(&self, slug: &str) -> Option<ShortcutEntry> {
         if let Ok(data) = self.data.read() {
-            data.get(slug).map(|entry| ShortcutEntry {
-                slug: entry.slug.clone(),
-                url: entry.url.clone(),
-                status: entry.status.clone(),
-                since: entry.since.clone(),
-                until: entry.until.clone(),
-            })
+            data.get(slug).cloned()
         } else {
             None
         }
     }
-    pub fn read_all(&self) -> Vec<ShortcutEntry> {
+    fn read_all(&self) -> Vec<ShortcutEntry> {
         let mut all: Vec<ShortcutEntry> = vec![];
         if let Ok(data) = self.data.read() {
             for (_, entry) in data.iter() {
@@ -55,124 +371,440 @@ impl Database {
         }
         all
     }
-    pub fn upsert(&self, slug: String, value: ShortcutEntry) -> bool {
-        if let Ok(mut locked) = self.data.write() {
-            locked.insert(slug, value);
-            true
-        } else {
-            false
+    fn upsert(
+        &self,
+        mut value: ShortcutEntry,
+        expected: Option<u64>,
+    ) -> Result<ShortcutEntry, WriteError> {
+        let mut locked = self.data.write().map_err(|_| WriteError::Failed)?;
+        let current = locked.get(&value.slug);
+        let revision = current.map_or(0, |entry| entry.revision);
+        if expected.is_some_and(|expected| expected != revision) {
+            return Err(WriteError::Conflict(revision));
         }
+        // A new shortcut replacing a trashed one starts its own history
+        if let Some(current) = current.filter(|current| !current.is_trashed()) {
+            value.created_at = current.created_at;
+            value.created_by = current.created_by.clone();
+        }
+        value.revision = revision + 1;
+        if !self.append(&codec::journal_record(&Record::Upsert(value.clone()))) {
+            return Err(WriteError::Failed);
+        }
+        self.record(Record::Upsert(value.clone()));
+        locked.insert(value.slug.clone(), value.clone());
+        Ok(value)
     }
-    pub fn delete(&self, slug: &String) -> bool {
-        if let Ok(mut locked) = self.data.write() {
-            locked.remove(slug).is_some()
-        } else {
-            false
+    fn delete(&self, slug: &str, expected: Option<u64>) -> Result<bool, WriteError> {
+        let mut locked = self.data.write().map_err(|_| WriteError::Failed)?;
+        let current = locked.get(slug).map_or(0, |entry| entry.revision);
+        if expected.is_some_and(|expected| expected != current) {
+            return Err(WriteError::Conflict(current));
         }
+        if current == 0 {
+            return Ok(false);
+        }
+        if !self.append(&codec::journal_record(&Record::Delete(slug.to_string()))) {
+            return Err(WriteError::Failed);
+        }
+        self.record(Record::Delete(slug.to_string()));
+        Ok(locked.remove(slug).is_some())
     }
 
-    pub fn to_csv(&self) -> String {
-        self.read_all()
-            .iter()
-            .map(|row| {
-                format!(
-                    "{},{},{},{},{}",
-                    row.slug, row.url, row.status, row.since, row.until,
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n")
+    fn resolve(&self, slug: &str) -> Option<ShortcutEntry> {
+        let data = self.data.read().ok()?;
+        match data.get(slug).filter(|entry| !entry.is_trashed()) {
+            Some(entry) => Some(entry.clone()),
+            None => data
+                .values()
+                .filter(|entry| !entry.is_trashed() && entry.answers(slug))
+                .min_by(|a, b| a.slug.cmp(&b.slug))
+                .cloned(),
+        }
+    }
+    fn lookalikes(&self, skeleton: &str) -> Vec<ShortcutEntry> {
+        let Ok(data) = self.data.read() else {
+            return vec![];
+        };
+        data.values()
+            .filter(|entry| !entry.is_trashed() && entry.names().any(|name| slugs::skeleton(name) == skeleton))
+            .cloned()
+            .collect()
+    }
+    fn merge(&self, value: ShortcutEntry) -> Result<bool, WriteError> {
+        let mut locked = self.data.write().map_err(|_| WriteError::Failed)?;
+        if locked
+            .get(&value.slug)
+            .is_some_and(|current| !value.supersedes(current))
+        {
+            return Ok(false);
+        }
+        if !self.append(&codec::journal_record(&Record::Upsert(value.clone()))) {
+            return Err(WriteError::Failed);
+        }
+        self.record(Record::Upsert(value.clone()));
+        locked.insert(value.slug.clone(), value);
+        Ok(true)
+    }
+    fn history(&self, slug: &str) -> Vec<ShortcutEntry> {
+        match self.history.lock() {
+            Ok(history) => history.entries.get(slug).cloned().unwrap_or_default(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Compacts the current state and journal into a new snapshot.
+    fn snapshot(&self) -> Option<String> {
+        let backups = self.backups.as_ref()?;
+        let data = self.data.write().ok()?;
+        let mut journal = self.journal.lock().ok()?;
+        let (name, log) = compact(backups, &self.instance_id, &data, true)?;
+        *journal = Some(log);
+        if let Ok(mut history) = self.history.lock() {
+            history.rewrite(backups);
+        }
+        self.dirty.store(false, Ordering::SeqCst);
+        Some(name)
+    }
+
+    fn backups(&self) -> Option<&Backups> {
+        self.backups.as_ref()
     }
 }
 
-impl Drop for Database {
-    fn drop(&mut self) {
-        if self.backup {
-            println!("Backing up database pre shutdown...");
-            match std::fs::create_dir("backups") {
-                Err(e) if e.kind() != ErrorKind::AlreadyExists => println!("{e}"),
-                _ => {}
-            };
-            std::fs::write(format!("./backups/{}.csv", self.instance_id), self.to_csv())
-                .expect("could not backup the database on exit");
+impl Database {
+    /// Adds a journaled mutation to the history.
+    /// Must be called while holding the data write lock to keep the history ordered.
+    fn record(&self, record: Record) {
+        if let Ok(mut history) = self.history.lock() {
+            history.record(record);
         }
     }
 }
 
-fn restore_data() -> HashMap<String, ShortcutEntry> {
-    let mut data: HashMap<String, ShortcutEntry> = HashMap::new();
-    let files = match std::fs::read_dir("./backups") {
-        Ok(entries) => {
-            let mut paths = vec![];
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    if entry.metadata().unwrap().is_file() {
-                        paths.push(entry.path());
+/// Append-only file of journal records, each sealed on its own.
+struct Log {
+    file: File,
+    seal: JournalSeal,
+}
+
+impl Log {
+    /// Creates the file, replacing an existing one, and writes the headers.
+    fn create(path: &Path, encryption: Option<&Encryption>) -> std::io::Result<Self> {
+        let mut seal = JournalSeal::new(encryption);
+        let mut file = File::create(path)?;
+        file.write_all(seal.header().as_bytes())?;
+        file.write_all(seal.seal(&codec::journal_header()).as_bytes())?;
+        file.sync_all()?;
+        Ok(Self { file, seal })
+    }
+
+    /// Appends the record and syncs it to disk.
+    fn append(&mut self, record: &str) -> std::io::Result<()> {
+        self.file.write_all(self.seal.seal(record).as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+/// The latest revisions of the entries up to the history limit, kept in an append-only log next to
+/// the journal. The log is rewritten on start and with every snapshot, dropping the revisions past
+/// the limit. A hard delete drops the history of the entry.
+struct History {
+    entries: HashMap<String, Vec<ShortcutEntry>>,
+    log: Option<Log>,
+    limit: usize,
+}
+
+impl History {
+    fn new(limit: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            log: None,
+            limit: limit.max(1),
+        }
+    }
+
+    /// Loads the history log and catches up with the restored data, which is
+    /// ahead of the log if the process stopped between journaling and recording a mutation.
+    fn open(backups: &Backups, data: &HashMap<String, ShortcutEntry>) -> Self {
+        let path = Path::new(&backups.directory).join(HISTORY);
+        let encryption = backups.encryption.as_ref();
+        let mut history = History::new(backups.history_limit);
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let content =
+                    seal::unseal_journal(&content, encryption, backups.migrate_unencrypted);
+                for record in codec::parse(&content, true) {
+                    match record {
+                        Ok(record) => history.apply(record),
+                        Err(rejection) => println!(
+                            "Skipping history line {}: {}",
+                            rejection.line, rejection.reason
+                        ),
                     }
                 }
             }
-            paths
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => println!("Skipping history {path:?}: {e}"),
         }
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                match std::fs::create_dir("./backups") {
-                    Err(e) if e.kind() != ErrorKind::AlreadyExists => println!("{e}"),
-                    _ => {},
+        history.rewrite(backups);
+        let mut slugs: Vec<&String> = data.keys().collect();
+        slugs.sort();
+        for slug in slugs {
+            let entry = &data[slug];
+            let latest = history.entries.get(slug).and_then(|revisions| revisions.last());
+            if latest.is_none_or(|latest| latest.revision != entry.revision) {
+                history.record(Record::Upsert(entry.clone()));
+            }
+        }
+        let mut purged: Vec<String> = history
+            .entries
+            .keys()
+            .filter(|slug| !data.contains_key(*slug))
+            .cloned()
+            .collect();
+        purged.sort();
+        for slug in purged {
+            history.record(Record::Delete(slug));
+        }
+        history
+    }
+
+    /// Replaces the log with one holding only the kept revisions, each record sealed in order
+    /// into a file of its own. If that fails the current log is kept.
+    fn rewrite(&mut self, backups: &Backups) {
+        let path = Path::new(&backups.directory).join(HISTORY);
+        let temporary = path.with_extension("log.tmp");
+        match Log::create(&temporary, backups.encryption.as_ref()).and_then(|mut log| {
+            let mut slugs: Vec<&String> = self.entries.keys().collect();
+            slugs.sort();
+            for slug in slugs {
+                for entry in &self.entries[slug] {
+                    log.append(&codec::journal_record(&Record::Upsert(entry.clone())))?;
                 }
-            } else {
-                println!("{e}");
             }
-            vec![]
+            fs::rename(&temporary, &path)?;
+            Ok(log)
+        }) {
+            Ok(log) => self.log = Some(log),
+            Err(e) => println!("Could not rewrite the history: {e}"),
+        }
+    }
+
+    /// Appends the mutation to the log and applies it. A failing write is only logged,
+    /// the mutation is already journaled and the next start catches up with the latest revision.
+    fn record(&mut self, record: Record) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(e) = log.append(&codec::journal_record(&record)) {
+                println!("Could not write to the history: {e}");
+            }
         }
-    };
+        self.apply(record);
+    }
 
-    if files.is_empty() {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Upsert(entry) => {
+                let revisions = self.entries.entry(entry.slug.clone()).or_default();
+                // A rewritten revision, like one replayed twice, replaces the earlier record
+                revisions.retain(|revision| revision.revision < entry.revision);
+                revisions.push(entry);
+                let excess = revisions.len().saturating_sub(self.limit);
+                revisions.drain(..excess);
+            }
+            Record::Delete(slug) => {
+                self.entries.remove(&slug);
+            }
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // Nothing to do if the shutdown already flushed the database
+        if self.backups.is_some() && self.dirty.load(Ordering::SeqCst) {
+            println!("Backing up database pre shutdown...");
+            if self.snapshot().is_none() {
+                panic!("could not backup the database on exit");
+            }
+        }
+    }
+}
+
+/// Writes a snapshot of the data as a new backup, optionally prunes the backups past their retention
+/// and starts a new journal. Returns the snapshot name and the opened journal on success.
+///
+/// The snapshot is written to a temporary file, synced and renamed before anything is pruned,
+/// so a crash at any point leaves either the old backups with their journal or the new snapshot behind.
+fn compact(
+    backups: &Backups,
+    instance_id: &str,
+    data: &HashMap<String, ShortcutEntry>,
+    prune: bool,
+) -> Option<(String, Log)> {
+    let dir = Path::new(&backups.directory);
+    let csv = codec::to_csv(&data.values().cloned().collect::<Vec<ShortcutEntry>>());
+    let name = write_snapshot(backups, instance_id, &csv)?;
+    if prune {
+        prune_backups(backups, &name);
+    }
+    let encryption = backups.encryption.as_ref();
+    match Log::create(&dir.join(JOURNAL), encryption) {
+        Ok(log) => Some((name, log)),
+        Err(e) => {
+            println!("Could not create the journal: {e}");
+            None
+        }
+    }
+}
+
+/// Restores the valid rows of the newest readable backup and replays the journal on top of it.
+/// Falls back to the seed file if there is neither a backup nor a journal.
+pub fn restore_data(backups: &Backups) -> HashMap<String, ShortcutEntry> {
+    let dir = Path::new(&backups.directory);
+    let encryption = backups.encryption.as_ref();
+    let mut data: HashMap<String, ShortcutEntry> = HashMap::new();
+    let files = backup_files(dir);
+    let journal = dir.join(JOURNAL);
+
+    if let Some(name) = &backups.restore_from {
+        // An explicitly chosen backup is restored as is, the journal belongs to a newer state
+        let path = files
+            .iter()
+            .find(|path| path.file_name().is_some_and(|file| file == name.as_str()))
+            .expect("the backup to restore from does not exist");
+        println!("Restoring from {path:?} without replaying the journal");
+        let report = load_data(&mut data, path, encryption, backups.migrate_unencrypted)
+            .expect("the backup to restore from could not be read");
+        report.check(path, backups.strict);
+    } else if files.is_empty() && metadata(&journal).is_err() {
         match metadata("./seed.csv") {
-            Ok(meta) if meta.is_file() => load_data(&mut data, &PathBuf::from("./seed.csv")),
-            _ => {},
+            Ok(meta) if meta.is_file() => {
+                let path = PathBuf::from("./seed.csv");
+                if let Some(report) = load_data(&mut data, &path, None, true) {
+                    report.check(&path, backups.strict);
+                }
+            }
+            _ => {}
         }
     } else {
-        for path in files {
-            load_data(&mut data, &path);
-            if let Err(e) = remove_file(&path) {
-                println!("{e}");
+        // Rejected rows are skipped, only a backup that cannot be read falls back to an older one,
+        // since the journal only applies on top of the newest backup
+        for path in &files {
+            if let Some(report) = load_data(&mut data, path, encryption, backups.migrate_unencrypted) {
+                report.check(path, backups.strict);
+                println!("Restoring from {path:?}");
+                if files.first() != Some(path) {
+                    println!("Restored an older backup, the journal might not apply cleanly");
+                }
+                break;
             }
         }
+        replay_journal(&mut data, &journal, encryption, backups.migrate_unencrypted);
     }
     data
 }
 
-fn load_data(data: &mut HashMap<String, ShortcutEntry>, path: &PathBuf) {
-    match fs::File::open(&path) {
-        Ok(file) => {
-            let buf = BufReader::new(file);
-            let regex =
-                Regex::new(
-                    r"^(?P<slug>[a-z0-9]+),(?P<url>https?://(www\.)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b([-a-zA-Z0-9()!@:%_\+.~#?&//=]*)),(?P<status>((un)?trusted)),(?P<since>\d+),(?P<until>\d+)$"
-                ).expect("invalid regex");
-            for content in buf.lines().flatten() {
-                if let Some(capture) = regex.captures(&content) {
-                    if let (Some(slug), Some(url), Some(status), Some(since), Some(until)) = (
-                        capture.name("slug"),
-                        capture.name("url"),
-                        capture.name("status"),
-                        capture.name("since"),
-                        capture.name("until"),
-                    ) {
-                        data.insert(
-                            slug.as_str().to_string(),
-                            ShortcutEntry {
-                                slug: slug.as_str().to_string(),
-                                url: url.as_str().to_string(),
-                                status: status.as_str().to_string(),
-                                since: since.as_str().to_string(),
-                                until: until.as_str().to_string(),
-                            },
-                        );
+fn replay_journal(
+    data: &mut HashMap<String, ShortcutEntry>,
+    path: &PathBuf,
+    encryption: Option<&Encryption>,
+    unencrypted: bool,
+) {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let mut replayed = 0;
+            let content = seal::unseal_journal(&content, encryption, unencrypted);
+            for record in codec::parse(&content, true) {
+                match record {
+                    Ok(Record::Upsert(entry)) => {
+                        data.insert(entry.slug.clone(), entry);
+                        replayed += 1;
                     }
+                    Ok(Record::Delete(slug)) => {
+                        data.remove(&slug);
+                        replayed += 1;
+                    }
+                    Err(rejection) => println!(
+                        "Skipping journal line {}: {}",
+                        rejection.line, rejection.reason
+                    ),
                 }
             }
+            if replayed > 0 {
+                println!("Replayed {replayed} journaled mutations");
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => println!("Skipping journal {path:?}: {e}"),
+    }
+}
+
+/// Rows of a seed file or backup that were loaded and the ones the validation rejected.
+struct LoadReport {
+    loaded: usize,
+    rejected: Vec<Rejection>,
+}
+
+impl LoadReport {
+    /// Logs the report with every rejected row. In strict mode a single one stops the start,
+    /// before a later snapshot replaces the file and the rotation prunes the good backups.
+    fn check(&self, path: &Path, strict: bool) {
+        if self.rejected.is_empty() {
+            println!("Loaded {} rows of {path:?}", self.loaded);
+            return;
+        }
+        println!(
+            "Loaded {} rows of {path:?}, rejected {}:",
+            self.loaded,
+            self.rejected.len()
+        );
+        for rejection in &self.rejected {
+            println!("  line {}: {}", rejection.line, rejection.reason);
+        }
+        if strict {
+            panic!("{path:?} has rejected rows, refusing to start in strict mode!");
+        }
+    }
+}
+
+/// Loads all valid rows of a csv file into the data. With a key only encrypted files are loaded,
+/// unless `unencrypted` allows files written before the key was configured.
+/// Returns the report or `None` if the file could not be read, decrypted or verified.
+fn load_data(
+    data: &mut HashMap<String, ShortcutEntry>,
+    path: &PathBuf,
+    encryption: Option<&Encryption>,
+    unencrypted: bool,
+) -> Option<LoadReport> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let content = match seal::unseal(&content, encryption, unencrypted) {
+                Ok(content) => content,
+                Err(reason) => {
+                    println!("Skipping backup {path:?}: {reason}");
+                    return None;
+                }
+            };
+            let mut report = LoadReport {
+                loaded: 0,
+                rejected: vec![],
+            };
+            for record in codec::parse(&content, false) {
+                match record {
+                    Ok(Record::Upsert(entry)) => {
+                        data.insert(entry.slug.clone(), entry);
+                        report.loaded += 1;
+                    }
+                    Ok(Record::Delete(_)) => {}
+                    Err(rejection) => report.rejected.push(rejection),
+                }
+            }
+            Some(report)
+        }
+        Err(e) => {
+            println!("Skipping seeding {path:?}: {e}");
+            None
         }
-        Err(e) => println!("Skipping seeding {path:?}: {e}"),
     }
 }
@@ -0,0 +1,57 @@
+use crate::codec::{self, Format};
+use crate::configuration::{GitOps, Slugs};
+use crate::slugs;
+use crate::storage::{ImportError, ImportReport, Storage, Strategy};
+use std::fs;
+
+/// Identity the reconciled changes are attributed to.
+const AUTHOR: &str = "gitops";
+
+/// Applies the seed file: shortcuts in it are added or reverted to it, shortcuts missing in it
+/// are moved to the trash with `prune`. A file with rejected rows is not applied at all,
+/// so a broken edit cannot remove shortcuts.
+pub fn reconcile(
+    storage: &dyn Storage,
+    config: &GitOps,
+    policy: &Slugs,
+) -> Result<ImportReport, String> {
+    let records = read(config, policy)?;
+    let strategy = if config.prune {
+        Strategy::ReplaceAll
+    } else {
+        Strategy::MergeOverwrite
+    };
+    storage
+        .import(records, strategy, false, AUTHOR)
+        .map_err(|error| match error {
+            ImportError::Rejected(report) => format!(
+                "{} rejected rows, {} conflicts",
+                report.rejected.len(),
+                report.conflicts.len()
+            ),
+            ImportError::Failed => "the changes could not be stored".to_string(),
+        })
+}
+
+/// Compares the shortcuts with the seed file without changing anything.
+/// Shortcuts that only exist at runtime are listed as `removed`, as a `prune` would remove them.
+pub fn drift(
+    storage: &dyn Storage,
+    config: &GitOps,
+    policy: &Slugs,
+) -> Result<ImportReport, String> {
+    storage
+        .import(read(config, policy)?, Strategy::ReplaceAll, true, AUTHOR)
+        .map_err(|_| "the shortcuts could not be read".to_string())
+}
+
+fn read(
+    config: &GitOps,
+    policy: &Slugs,
+) -> Result<Vec<Result<codec::Record, codec::Rejection>>, String> {
+    let content = fs::read_to_string(&config.path)
+        .map_err(|e| format!("could not read {}: {e}", config.path))?;
+    Ok(codec::import(Format::from_path(&config.path), &content, &|entry| {
+        slugs::check_import(policy, AUTHOR, entry)
+    }))
+}
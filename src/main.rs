@@ -1,37 +1,153 @@
-use crate::{configuration::get_config, database::Database, routes::*};
+use crate::{
+    configuration::{get_config, ServerMode},
+    replication::ReplicationStatus,
+    routes::*,
+};
 use actix_files::Files;
-use actix_web::{middleware::Logger, web, App, HttpServer};
-use std::sync::Arc;
+use actix_web::{
+    dev::ServerHandle,
+    middleware::Logger,
+    rt::{self, signal},
+    web, App, HttpServer,
+};
+use std::{
+    process::exit,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tera::Tera;
 
+mod codec;
 mod configuration;
 mod database;
+mod gitops;
+mod replication;
 mod routes;
 mod schema;
+mod seal;
+mod slugs;
+mod sqlite;
+mod storage;
 mod tests;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let config = get_config();
-    let database = Arc::new(Database::new(true));
+    let database = storage::open(&config);
     let port = std::env::var("PORT").unwrap_or("8080".into());
 
+    if let Some(interval) = config.server.snapshot_interval {
+        // Compacts the journal while running, the final snapshot is written by the flush on shutdown
+        let database = Arc::downgrade(&database);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            match database.upgrade() {
+                Some(database) => {
+                    if database.snapshot().is_none() {
+                        println!("Periodic snapshot failed");
+                    }
+                }
+                None => break,
+            }
+        });
+    }
+
+    // A follower mirrors the purges of its primary
+    if config.server.mode == ServerMode::Primary {
+        let database = Arc::downgrade(&database);
+        let purge_after = Duration::from_secs(config.trash.purge_after);
+        thread::spawn(move || loop {
+            match database.upgrade() {
+                Some(database) => {
+                    let before = SystemTime::now()
+                        .checked_sub(purge_after)
+                        .and_then(|before| before.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |before| before.as_millis());
+                    for slug in database.purge(before) {
+                        println!("Purged {slug} from the trash");
+                    }
+                }
+                None => break,
+            }
+            thread::sleep(Duration::from_secs(60 * 60));
+        });
+    }
+
+    if let Some(gitops) = config.gitops.clone() {
+        let database = Arc::downgrade(&database);
+        let slugs = config.slugs.clone();
+        thread::spawn(move || loop {
+            match database.upgrade() {
+                Some(database) => match gitops::reconcile(database.as_ref(), &gitops, &slugs) {
+                    Ok(report) => {
+                        for (change, slugs) in [
+                            ("Added", &report.added),
+                            ("Reverted", &report.changed),
+                            ("Trashed", &report.removed),
+                        ] {
+                            for slug in slugs {
+                                println!("{change} {slug} to match {}", gitops.path);
+                            }
+                        }
+                    }
+                    Err(e) => println!("Could not reconcile {}: {e}", gitops.path),
+                },
+                None => break,
+            }
+            thread::sleep(Duration::from_secs(gitops.interval));
+        });
+    }
+
+    let status = Arc::new(ReplicationStatus::new(&config.replication.peers));
+    if !config.replication.peers.is_empty() {
+        let database = Arc::downgrade(&database);
+        let status = status.clone();
+        let replication = config.replication.clone();
+        let mirror = config.server.mode == ServerMode::Follower;
+        thread::spawn(move || loop {
+            match database.upgrade() {
+                Some(database) => {
+                    replication::sync(database.as_ref(), &replication, mirror, &status)
+                }
+                None => break,
+            }
+            thread::sleep(Duration::from_secs(replication.interval));
+        });
+    }
+
     println!("Starting HTTP server at http://localhost:{port}");
-    HttpServer::new(move || {
+    let storage = database.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .service(health)
             .app_data(web::Data::new(config.clone()))
             .app_data(web::Data::new(database.clone()))
+            .app_data(web::Data::new(status.clone()))
+            // Imports on POST /store carry the whole dataset
+            .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
             .app_data(web::Data::new(
                 Tera::new("./templates/**/*").expect("no templates found"),
             ))
             .service(find)
+            .service(details)
             .service(create)
             .service(delete)
+            .service(untrash)
+            .service(history)
+            .service(rollback)
+            .service(rename)
             .service(share)
             .service(store)
+            .service(restore)
+            .service(import)
+            .service(list_snapshots)
+            .service(create_snapshot)
+            .service(download_snapshot)
             .service(dashboard)
             .service(code)
+            .service(replication_entries)
+            .service(drift)
             .service(Files::new("/assets/", "./assets/").disable_content_disposition())
             .wrap(Logger::default())
     })
@@ -41,6 +157,42 @@ async fn main() -> std::io::Result<()> {
             .expect("the provided port is not a u16 number"),
     ))
     .expect("Could not bind the http server on port 8080")
-    .run()
-    .await
+    .disable_signals()
+    .run();
+    stop_on_signals(server.handle());
+    server.await?;
+
+    // Workers might still hold clones of the storage, so it is flushed explicitly instead of on drop
+    println!("Flushing the database...");
+    if storage.flush() {
+        println!("Shutdown complete");
+        Ok(())
+    } else {
+        println!("Shutdown failed, the database could not be flushed");
+        exit(1)
+    }
+}
+
+/// Stops the server gracefully on SIGTERM or SIGINT. In-flight requests are drained before `run` returns.
+fn stop_on_signals(handle: ServerHandle) {
+    #[cfg(unix)]
+    {
+        let handle = handle.clone();
+        rt::spawn(async move {
+            match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                    println!("Received SIGTERM, draining requests...");
+                    handle.stop(true).await;
+                }
+                Err(e) => println!("Could not listen for SIGTERM: {e}"),
+            }
+        });
+    }
+    rt::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            println!("Received SIGINT, draining requests...");
+            handle.stop(true).await;
+        }
+    });
 }
@@ -0,0 +1,195 @@
+use crate::codec::{self, Record};
+use crate::configuration::Replication;
+use crate::database::ShortcutEntry;
+use crate::schema::PeerLag;
+use crate::storage::{Storage, WriteError};
+use actix_web::http::header::HeaderMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Outcome of the latest pulls from every peer, reported on `/health`.
+#[derive(Default)]
+pub struct ReplicationStatus {
+    peers: RwLock<HashMap<String, PeerSync>>,
+}
+
+#[derive(Clone, Default)]
+struct PeerSync {
+    /// Milliseconds since the UNIX epoch of the last successful pull
+    synced_at: Option<u128>,
+    /// Reason the last pull failed, cleared by the next successful one
+    error: Option<String>,
+}
+
+impl ReplicationStatus {
+    pub fn new(peers: &[String]) -> Self {
+        Self {
+            peers: RwLock::new(
+                peers
+                    .iter()
+                    .map(|peer| (peer.clone(), PeerSync::default()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Lag of every peer, the time since its last successful pull. `None` if it was never pulled.
+    pub fn report(&self) -> Vec<PeerLag> {
+        let now = now();
+        let mut report: Vec<PeerLag> = match self.peers.read() {
+            Ok(peers) => peers
+                .iter()
+                .map(|(peer, sync)| PeerLag {
+                    peer: peer.clone(),
+                    synced_at: sync.synced_at,
+                    lag: sync.synced_at.map(|synced_at| now.saturating_sub(synced_at)),
+                    error: sync.error.clone(),
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+        report.sort_by(|a, b| a.peer.cmp(&b.peer));
+        report
+    }
+
+    fn update(&self, peer: &str, result: Result<u128, String>) {
+        if let Ok(mut peers) = self.peers.write() {
+            let sync = peers.entry(peer.to_string()).or_default();
+            match result {
+                Ok(synced_at) => {
+                    sync.synced_at = Some(synced_at);
+                    sync.error = None;
+                }
+                Err(error) => sync.error = Some(error),
+            }
+        }
+    }
+}
+
+/// Pulls the entries of every peer once and merges them, the newest revision of an entry wins.
+/// Trashed entries replicate like any other change, purges happen on every instance on their own.
+///
+/// A mirror takes the entries of its peer as they are instead, even older revisions, and deletes
+/// the entries the peer does not have anymore.
+pub fn sync(storage: &dyn Storage, config: &Replication, mirror: bool, status: &ReplicationStatus) {
+    for peer in &config.peers {
+        let started = now();
+        let result = pull(peer, &config.secret).and_then(|(entries, complete)| {
+            let merged = apply(storage, entries, mirror, complete)
+                .map_err(|_| "could not store the replicated entries".to_string())?;
+            if merged > 0 {
+                println!("Replicated {merged} changes from {peer}");
+            }
+            Ok(started)
+        });
+        if let Err(e) = &result {
+            println!("Could not replicate from {peer}: {e}");
+        }
+        status.update(peer, result);
+    }
+}
+
+/// Stores the pulled entries and returns the number of changes.
+/// An incomplete answer is only merged, even by a mirror, as missing entries may just have been skipped.
+pub fn apply(
+    storage: &dyn Storage,
+    entries: Vec<ShortcutEntry>,
+    mirror: bool,
+    complete: bool,
+) -> Result<usize, WriteError> {
+    let mirror = mirror && complete;
+    let mut changes = 0;
+    let mut pulled = HashSet::new();
+    for entry in entries {
+        pulled.insert(entry.slug.clone());
+        // An older revision only replaces the stored entry in a mirror, like after the peer restored a backup
+        if mirror
+            && storage
+                .read(&entry.slug)
+                .is_some_and(|current| current != entry && !entry.supersedes(&current))
+        {
+            storage.delete(&entry.slug, None)?;
+        }
+        if storage.merge(entry)? {
+            changes += 1;
+        }
+    }
+    if mirror {
+        for entry in storage.read_all() {
+            if !pulled.contains(&entry.slug) && storage.delete(&entry.slug, None)? {
+                changes += 1;
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Fetches all entries of a peer, trashed ones included.
+/// Also returns whether every entry was valid, a mirror only deletes entries based on a complete answer.
+fn pull(peer: &str, secret: &str) -> Result<(Vec<ShortcutEntry>, bool), String> {
+    // The secret is passed on stdin to keep it out of the process list
+    let mut child = Command::new("/usr/bin/curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .args(["--max-time", "30"])
+        .args(["-H", "@-"])
+        .arg(format!("{}/replication/entries", peer.trim_end_matches('/')))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run curl: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "Authorization: Bearer {secret}")
+            .map_err(|e| format!("could not run curl: {e}"))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("could not run curl: {e}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let csv = String::from_utf8(output.stdout).map_err(|_| "the answer is not utf-8")?;
+    let mut entries = vec![];
+    let mut complete = true;
+    for record in codec::parse(&csv, false) {
+        match record {
+            Ok(Record::Upsert(entry)) => entries.push(entry),
+            Ok(Record::Delete(_)) => {}
+            Err(rejection) => {
+                complete = false;
+                println!(
+                    "Skipping replicated line {} from {peer}: {}",
+                    rejection.line, rejection.reason
+                )
+            }
+        }
+    }
+    Ok((entries, complete))
+}
+
+/// Whether the request carries the shared secret. Always false without a secret.
+pub fn authorized(config: &Replication, headers: &HeaderMap) -> bool {
+    if config.secret.is_empty() {
+        return false;
+    }
+    let expected = format!("Bearer {}", config.secret);
+    headers
+        .get("Authorization")
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis()
+}
@@ -1,12 +1,18 @@
 use crate::{
+    codec::{self, Format},
     configuration::*,
-    database::{Database, ShortcutEntry},
+    database::{ShortcutEntry, Trust},
+    gitops,
+    slugs,
+    replication::{self, ReplicationStatus},
     schema::*,
+    seal,
+    storage::{ImportError, Storage, Strategy, WriteError},
 };
 use actix_web::{
     delete, get,
-    http::header,
-    put,
+    http::{header, StatusCode},
+    post, put,
     web::{Data, Json, Path, Query},
     HttpRequest, HttpResponse, Responder,
 };
@@ -26,7 +32,7 @@ use tera::{Context, Tera};
 
 #[get("/")]
 pub async fn dashboard(
-    data: Data<Arc<Database>>,
+    data: Data<Arc<dyn Storage>>,
     tera: Data<Tera>,
     config: Data<Configuration>,
     req: HttpRequest,
@@ -35,24 +41,47 @@ pub async fn dashboard(
         return res;
     }
 
-    let items = data
+    let (trashed, live): (Vec<ShortcutEntry>, Vec<ShortcutEntry>) = data
         .read_all()
+        .into_iter()
+        .partition(ShortcutEntry::is_trashed);
+    let items = live
         .iter()
         .map(|item| ShortcutItem {
             slug: item.slug.clone(),
             url: item.url.clone(),
-            status: item.status.clone(),
+            status: item.trust,
             now: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("time went backwards")
                 .as_millis(),
-            since: item.since.parse().expect("not valid UNIX time."),
-            until: item.until.parse().expect("not valid UNIX time."),
+            since: item.since,
+            until: item.until,
+            revision: item.revision,
+            created_at: item.created_at,
+            created_by: item.created_by.clone(),
+            updated_at: item.updated_at,
+            updated_by: item.updated_by.clone(),
+            aliases: item.aliases.clone(),
+            forward_to: item.forward_to.clone(),
+            redirect: item.redirect,
+            query: item.query,
+        })
+        .collect();
+    let mut trash: Vec<TrashItem> = trashed
+        .into_iter()
+        .map(|item| TrashItem {
+            purge_at: item.deleted_at + config.trash.purge_after as u128 * 1000,
+            slug: item.slug,
+            url: item.url,
+            deleted_at: item.deleted_at,
+            deleted_by: item.deleted_by,
         })
         .collect();
+    trash.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
     match tera.render(
         "dashboard.html",
-        &Context::from_serialize(ShortcutList { items }).expect(""),
+        &Context::from_serialize(ShortcutList { items, trash }).expect(""),
     ) {
         Ok(html) => HttpResponse::Ok()
             .insert_header(header::ContentType::html())
@@ -63,41 +92,257 @@ pub async fn dashboard(
 
 #[get("/store")]
 pub async fn store(
-    data: Data<Arc<Database>>,
+    data: Data<Arc<dyn Storage>>,
     config: Data<Configuration>,
+    query: Query<StoreQuery>,
     req: HttpRequest,
 ) -> impl Responder {
     if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
         return res;
     }
-    let csv = data.to_csv();
-    HttpResponse::Ok()
-        .append_header(("Content-Type", "text/csv; charset utf-8"))
-        .append_header(("Content-Disposition", "attachment; filename=\"seed.csv\""))
-        .body(csv)
+    let format = query.format.unwrap_or_default();
+    let live: Vec<ShortcutEntry> = data
+        .read_all()
+        .into_iter()
+        .filter(|entry| !entry.is_trashed())
+        .collect();
+    let export = codec::export(format, &live);
+    match &config.backups.encryption {
+        // Exports are encrypted like backups and can be imported again as they are
+        Some(encryption) => HttpResponse::Ok()
+            .append_header(("Content-Type", "application/octet-stream"))
+            .append_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"seed.{}.sealed\"", format.extension()),
+            ))
+            .body(seal::seal(&export, Some(encryption))),
+        None => HttpResponse::Ok()
+            .append_header(("Content-Type", format.content_type()))
+            .append_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"seed.{}\"", format.extension()),
+            ))
+            .body(export),
+    }
+}
+
+#[post("/store")]
+pub async fn restore(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    query: Query<StoreQuery>,
+    body: String,
+    req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+    let format = query.format.or_else(|| {
+        req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Format::from_content_type)
+    });
+    let Some(format) = format else {
+        return HttpResponse::UnsupportedMediaType()
+            .body("Provide the format as csv, json, ndjson or yaml.");
+    };
+    // Uploads come from an authenticated identity, plain seed files are imported as well
+    let body = match seal::unseal(&body, config.backups.encryption.as_ref(), true) {
+        Ok(body) => body,
+        Err(reason) => return HttpResponse::UnprocessableEntity().body(reason),
+    };
+
+    // Nothing is imported unless every record is valid, the imported shortcuts replace the live ones
+    let records = codec::import(format, &body, &|entry| {
+        slugs::check_import(&config.slugs, &author, entry)
+    });
+    match data.import(records, Strategy::MergeOverwrite, false, &author) {
+        Ok(report) => HttpResponse::Ok().json(ImportAnswer {
+            imported: report.added.len() + report.changed.len() + report.unchanged.len(),
+        }),
+        Err(ImportError::Rejected(report)) => HttpResponse::UnprocessableEntity().json(ImportRejections {
+            rejected: report.rejected,
+            conflicts: report.conflicts,
+        }),
+        Err(ImportError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+#[post("/import")]
+pub async fn import(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    query: Query<ImportQuery>,
+    body: String,
+    req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+    // Uploads come from an authenticated identity, plain seed files are imported as well
+    let body = match seal::unseal(&body, config.backups.encryption.as_ref(), true) {
+        Ok(body) => body,
+        Err(reason) => return HttpResponse::UnprocessableEntity().body(reason),
+    };
+    let records = codec::import(query.format.unwrap_or_default(), &body, &|entry| {
+        slugs::check_import(&config.slugs, &author, entry)
+    });
+    match data.import(records, query.strategy, query.dry_run, &author) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(ImportError::Rejected(report)) => HttpResponse::UnprocessableEntity().json(report),
+        Err(ImportError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+#[get("/snapshots")]
+pub async fn list_snapshots(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+        return res;
+    }
+    let items = data
+        .snapshots()
+        .into_iter()
+        .map(|snapshot| SnapshotItem {
+            name: snapshot.name,
+            size: snapshot.size,
+            modified: snapshot.modified,
+        })
+        .collect();
+    HttpResponse::Ok().json(SnapshotList { items })
+}
+
+#[post("/snapshots")]
+pub async fn create_snapshot(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+        return res;
+    }
+    match data.snapshot() {
+        Some(name) => HttpResponse::Created().json(GetSnapshot { name }),
+        None => HttpResponse::InternalServerError().body("The snapshot could not be written."),
+    }
+}
+
+#[get("/snapshots/{name}")]
+pub async fn download_snapshot(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetSnapshot>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+        return res;
+    }
+    // Snapshots are served sealed, encrypted ones are no csv
+    let content_type = match config.backups.encryption {
+        Some(_) => "application/octet-stream",
+        None => "text/csv; charset=utf-8",
+    };
+    match data.read_snapshot(&path.name) {
+        Some(csv) => HttpResponse::Ok()
+            .append_header(("Content-Type", content_type))
+            .append_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", path.name),
+            ))
+            .body(csv),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/api/shortcuts/{slug}")]
+pub async fn details(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetShortcut>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+        return res;
+    }
+    match data.read(&stored_slug(data.as_ref().as_ref(), &config.slugs.normalize, &path.slug)).filter(|entry| !entry.is_trashed()) {
+        Some(entry) => HttpResponse::Ok()
+            .insert_header(etag(entry.revision))
+            .json(ShortcutDetails {
+                slug: entry.slug,
+                url: entry.url,
+                status: entry.trust,
+                since: entry.since,
+                until: entry.until,
+                revision: entry.revision,
+                created_at: entry.created_at,
+                created_by: entry.created_by,
+                updated_at: entry.updated_at,
+                updated_by: entry.updated_by,
+                aliases: entry.aliases,
+                forward_to: entry.forward_to,
+                redirect: entry.redirect,
+                query: entry.query,
+            }),
+        None => HttpResponse::NotFound().finish(),
+    }
 }
 
 #[get("/s/{slug}")]
 pub async fn find(
-    data: Data<Arc<Database>>,
+    data: Data<Arc<dyn Storage>>,
     tera: Data<Tera>,
     config: Data<Configuration>,
     path: Path<GetShortcut>,
+    req: HttpRequest,
 ) -> impl Responder {
-    match data.read(&path.slug) {
+    let now: u128 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis();
+    let slug = slugs::normalize(&config.slugs.normalize, &path.slug);
+    // Slugs that could not be normalized on start are still found as stored
+    let mut found = data
+        .resolve(&slug)
+        .or_else(|| (slug != path.slug).then(|| data.resolve(&path.slug)).flatten());
+    // Renamed slugs forward to their new slug until their window ends, chains are cut off
+    for _ in 0..5 {
+        match &found {
+            Some(entry) if entry.is_tombstone() && now >= entry.since && now <= entry.until => {
+                found = data.resolve(&entry.forward_to);
+            }
+            _ => break,
+        }
+    }
+    match found {
         Some(result) => {
-            let available_since: u128 = result.since.parse().expect("not valid UNIX time.");
-            let available_until: u128 = result.until.parse().expect("not valid UNIX time.");
-            let now: u128 = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("time went backwards")
-                .as_millis();
+            let available_since = result.since;
+            let available_until = result.until;
+            let url = result
+                .query
+                .unwrap_or(config.redirects.query)
+                .apply(&result.url, req.query_string());
 
             if now >= available_since && now <= available_until {
                 // Approval confirm url
-                if result.status == "untrusted" {
+                if result.trust == Trust::Untrusted {
                     let ctx = Context::from_serialize(Approval {
-                        url: result.url,
+                        url,
                         dir: config.i18n.dir.clone(),
                         lang: config.i18n.lang.clone(),
                         label: config.i18n.approval.label.clone(),
@@ -112,9 +357,15 @@ pub async fn find(
                 }
                 // Redirect
                 else {
-                    return HttpResponse::SeeOther()
-                        .append_header(("Location", result.url))
-                        .finish();
+                    let status = match result.redirect {
+                        0 => config.redirects.status,
+                        status => status,
+                    };
+                    return HttpResponse::build(
+                        StatusCode::from_u16(status).unwrap_or(StatusCode::SEE_OTHER),
+                    )
+                    .append_header(("Location", url))
+                    .finish();
                 }
             } else if now < available_since {
                 // Countdown
@@ -191,71 +442,487 @@ pub async fn share(
 
 #[put("/s")]
 pub async fn create(
-    data: Data<Arc<Database>>,
+    data: Data<Arc<dyn Storage>>,
     body: Json<PutShortcut>,
     config: Data<Configuration>,
     req: HttpRequest,
 ) -> impl Responder {
-    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
         return res;
     }
 
-    // Validation
-    if body.slug.len() > 64 || body.slug.is_empty() {
-        return HttpResponse::UnprocessableEntity().body("Provide a non empty slug (max. 64).");
+    let trust = if body.approval {
+        Trust::Untrusted
+    } else {
+        Trust::Trusted
+    };
+    let policy = &config.slugs.normalize;
+    let mut entry = ShortcutEntry::new(
+        body.slug.clone(),
+        body.url.clone(),
+        trust,
+        body.since,
+        body.until,
+    );
+    entry.aliases = body.aliases.clone();
+    entry.redirect = body.redirect;
+    entry.query = body.query;
+    slugs::normalize_entry(policy, &mut entry);
+    let generated = entry.slug.is_empty();
+    if generated {
+        entry.slug = generate_slug(data.as_ref().as_ref(), &config.slugs);
     }
+    entry.stamp(&author);
 
-    let regex =
-        Regex::new(r"^https?://(www\.)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b([-a-zA-Z0-9()!@:%_\+.~#?&//=]*)$")
-        .expect("invalid url regex");
-    if regex.captures(&body.url).is_none() {
-        return HttpResponse::UnprocessableEntity().body("The provided URL is invalid.");
+    // Validation
+    if let Err(message) = entry.validate() {
+        return HttpResponse::UnprocessableEntity().body(message);
     }
-
-    // Insert
-    let status = if body.approval {
-        "untrusted"
-    } else {
-        "trusted"
-    };
-    let entry = ShortcutEntry {
-        slug: body.slug.clone(),
-        url: body.url.clone(),
-        status: status.to_string(),
-        since: body.since.to_string(),
-        until: body.until.to_string(),
+    let names = || std::iter::once(&entry.slug).chain(entry.aliases.iter());
+    if !config.slugs.override_by.contains(&author) {
+        if let Some(message) = names().find_map(|name| slugs::restriction(&config.slugs, name)) {
+            return HttpResponse::UnprocessableEntity().body(message);
+        }
+    }
+    if let Some(res) = taken(data.as_ref().as_ref(), &entry) {
+        return res;
+    }
+    if let Some(message) = names()
+        .find_map(|name| slugs::lookalike(policy, data.as_ref().as_ref(), &entry.slug, name))
+    {
+        return HttpResponse::UnprocessableEntity().body(message);
+    }
+    let expected = match precondition(&req, || data.read(&entry.slug)) {
+        Ok(expected) => expected,
+        Err(res) => return res,
     };
 
-    if data.upsert(body.slug.clone(), entry) {
-        HttpResponse::Created().json(PutShortcutAnwser {
-            slug: body.slug.clone(),
-        })
-    } else {
-        HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+    // Insert, a generated slug must still be free and is drawn again otherwise
+    let mut result = data.upsert(entry.clone(), if generated { Some(0) } else { expected });
+    for _ in 0..10 {
+        if !generated || !matches!(result, Err(WriteError::Conflict(_))) {
+            break;
+        }
+        entry.slug = generate_slug(data.as_ref().as_ref(), &config.slugs);
+        result = data.upsert(entry.clone(), Some(0));
+    }
+    if generated && matches!(result, Err(WriteError::Conflict(_))) {
+        return HttpResponse::InternalServerError()
+            .body("Could not find a free slug, configure longer generated slugs.");
+    }
+    match result {
+        Ok(entry) => HttpResponse::Created()
+            .insert_header(etag(entry.revision))
+            .json(PutShortcutAnwser {
+                slug: entry.slug,
+                revision: entry.revision,
+                created_at: entry.created_at,
+                created_by: entry.created_by,
+                updated_at: entry.updated_at,
+                updated_by: entry.updated_by,
+            }),
+        Err(WriteError::Conflict(current)) => conflict(current),
+        Err(WriteError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
     }
 }
 
 #[delete("/s")]
 pub async fn delete(
-    data: Data<Arc<Database>>,
+    data: Data<Arc<dyn Storage>>,
     body: Json<DeleteShortcut>,
     config: Data<Configuration>,
     req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+
+    let slug = stored_slug(data.as_ref().as_ref(), &config.slugs.normalize, &body.slug);
+    let expected = match precondition(&req, || data.read(&slug)) {
+        Ok(expected) => expected,
+        Err(res) => return res,
+    };
+    // Deleted shortcuts are kept in the trash until they are purged
+    match data.trash(&slug, expected, &author) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(WriteError::Conflict(current)) => conflict(current),
+        Err(WriteError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+#[post("/api/shortcuts/{slug}/restore")]
+pub async fn untrash(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetShortcut>,
+    req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+    let slug = stored_slug(data.as_ref().as_ref(), &config.slugs.normalize, &path.slug);
+    let expected = match precondition(&req, || data.read(&slug)) {
+        Ok(expected) => expected,
+        Err(res) => return res,
+    };
+    if let Some(trashed) = data.read(&slug).filter(ShortcutEntry::is_trashed) {
+        if let Some(res) = taken(data.as_ref().as_ref(), &trashed) {
+            return res;
+        }
+    }
+    match data.untrash(&slug, expected, &author) {
+        Ok(Some(entry)) => HttpResponse::Ok()
+            .insert_header(etag(entry.revision))
+            .json(PutShortcutAnwser {
+                slug: entry.slug,
+                revision: entry.revision,
+                created_at: entry.created_at,
+                created_by: entry.created_by,
+                updated_at: entry.updated_at,
+                updated_by: entry.updated_by,
+            }),
+        Ok(None) => HttpResponse::NotFound().body("The shortcut is not in the trash."),
+        Err(WriteError::Conflict(current)) => conflict(current),
+        Err(WriteError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+#[get("/api/shortcuts/{slug}/history")]
+pub async fn history(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetShortcut>,
+    query: Query<HistoryQuery>,
+    req: HttpRequest,
 ) -> impl Responder {
     if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
         return res;
     }
+    let slug = stored_slug(data.as_ref().as_ref(), &config.slugs.normalize, &path.slug);
+    if let Some(at) = query.at {
+        return match data.revision_at(&slug, at) {
+            Some(entry) => HttpResponse::Ok().json(history_item(entry)),
+            None => HttpResponse::NotFound().body("The shortcut did not exist at that time."),
+        };
+    }
+    let revisions = data.history(&slug);
+    if revisions.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().json(revisions.into_iter().map(history_item).collect::<Vec<HistoryItem>>())
+}
 
-    if data.delete(&body.slug) {
-        HttpResponse::Ok().finish()
-    } else {
-        HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+#[post("/api/shortcuts/{slug}/rollback")]
+pub async fn rollback(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetShortcut>,
+    body: Json<RollbackShortcut>,
+    req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+    let slug = stored_slug(data.as_ref().as_ref(), &config.slugs.normalize, &path.slug);
+    let expected = match precondition(&req, || data.read(&slug)) {
+        Ok(expected) => expected,
+        Err(res) => return res,
+    };
+    let revision = match (body.revision, body.at) {
+        (Some(revision), _) => Some(revision),
+        (None, Some(at)) => data.revision_at(&slug, at).map(|entry| entry.revision),
+        (None, None) => {
+            return HttpResponse::BadRequest().body("Provide the revision or time to roll back to.")
+        }
+    };
+    let Some(revision) = revision else {
+        return HttpResponse::NotFound().body("The revision to roll back to does not exist.");
+    };
+    // A rollback restores a trashed entry, whose names may have been taken in the meantime
+    if let Some(trashed) = data.read(&slug).filter(ShortcutEntry::is_trashed) {
+        if let Some(res) = taken(data.as_ref().as_ref(), &trashed) {
+            return res;
+        }
+    }
+    match data.rollback(&slug, revision, expected, &author) {
+        Ok(Some(entry)) => HttpResponse::Ok()
+            .insert_header(etag(entry.revision))
+            .json(PutShortcutAnwser {
+                slug: entry.slug,
+                revision: entry.revision,
+                created_at: entry.created_at,
+                created_by: entry.created_by,
+                updated_at: entry.updated_at,
+                updated_by: entry.updated_by,
+            }),
+        Ok(None) => HttpResponse::NotFound().body("The revision to roll back to does not exist."),
+        Err(WriteError::Conflict(current)) => conflict(current),
+        Err(WriteError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
     }
 }
 
+#[post("/api/shortcuts/{slug}/rename")]
+pub async fn rename(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    path: Path<GetShortcut>,
+    body: Json<RenameShortcut>,
+    req: HttpRequest,
+) -> impl Responder {
+    let author = match authorize(config.as_ref(), req.headers()).await {
+        Ok(author) => author,
+        Err(res) => return res,
+    };
+    if let Some(res) = read_only(config.as_ref()) {
+        return res;
+    }
+
+    let policy = &config.slugs.normalize;
+    let (slug, to) = (stored_slug(data.as_ref().as_ref(), policy, &path.slug), slugs::normalize(policy, &body.to));
+
+    // Validation
+    if to.len() > 64 || to.is_empty() || to.contains(char::is_whitespace) {
+        return HttpResponse::UnprocessableEntity()
+            .body("Provide a non empty slug without whitespace (max. 64).");
+    }
+    if to == slug {
+        return HttpResponse::UnprocessableEntity().body("Provide a different slug to rename to.");
+    }
+    if body.forward_until.is_some_and(|until| until > i64::MAX as u128) {
+        return HttpResponse::UnprocessableEntity().body("The forwarding end is out of range.");
+    }
+    if !config.slugs.override_by.contains(&author) {
+        if let Some(message) = slugs::restriction(&config.slugs, &to) {
+            return HttpResponse::UnprocessableEntity().body(message);
+        }
+    }
+    if let Some(owner) = data
+        .resolve(&to)
+        .filter(|owner| owner.slug != slug && !(owner.is_tombstone() && owner.forward_to == slug))
+    {
+        return HttpResponse::UnprocessableEntity()
+            .body(format!("'{to}' is already taken by the shortcut '{}'.", owner.slug));
+    }
+    if let Some(message) = slugs::lookalike(policy, data.as_ref().as_ref(), &slug, &to) {
+        return HttpResponse::UnprocessableEntity().body(message);
+    }
+    let expected = match precondition(&req, || data.read(&slug)) {
+        Ok(expected) => expected,
+        Err(res) => return res,
+    };
+
+    // Without an end the old slug keeps forwarding until the tombstone is deleted
+    let forward_until = body.forward_until.unwrap_or(i64::MAX as u128);
+    match data.rename(&slug, &to, forward_until, expected, &author) {
+        Ok(Some(entry)) => HttpResponse::Ok()
+            .insert_header(etag(entry.revision))
+            .json(PutShortcutAnwser {
+                slug: entry.slug,
+                revision: entry.revision,
+                created_at: entry.created_at,
+                created_by: entry.created_by,
+                updated_at: entry.updated_at,
+                updated_by: entry.updated_by,
+            }),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(WriteError::Conflict(current)) => conflict(current),
+        Err(WriteError::Failed) => {
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+fn history_item(entry: ShortcutEntry) -> HistoryItem {
+    HistoryItem {
+        revision: entry.revision,
+        url: entry.url,
+        status: entry.trust,
+        since: entry.since,
+        until: entry.until,
+        updated_at: entry.updated_at,
+        updated_by: entry.updated_by,
+        deleted_at: entry.deleted_at,
+        deleted_by: entry.deleted_by,
+        aliases: entry.aliases,
+        forward_to: entry.forward_to,
+        redirect: entry.redirect,
+        query: entry.query,
+    }
+}
+
+/// Stored slug the requested one refers to: the normalized slug, unless only the slug as requested
+/// is stored, like one restored before the policy was tightened that could not be normalized.
+fn stored_slug(data: &dyn Storage, policy: &Normalization, slug: &str) -> String {
+    let normalized = slugs::normalize(policy, slug);
+    if normalized != slug && data.read(&normalized).is_none() && data.read(slug).is_some() {
+        return slug.into();
+    }
+    normalized
+}
+
+/// Random normalized slug that is neither reserved, contains a blocked word nor looks like a name
+/// of another shortcut, as far as one is found.
+fn generate_slug(data: &dyn Storage, config: &Slugs) -> String {
+    let draw = || slugs::normalize(&config.normalize, &slugs::generate(&config.generate));
+    let mut slug = draw();
+    for _ in 0..100 {
+        if slugs::restriction(config, &slug).is_none()
+            && slugs::lookalike(&config.normalize, data, &slug, &slug).is_none()
+        {
+            break;
+        }
+        slug = draw();
+    }
+    slug
+}
+
+/// Rejects changes on a follower, they are only accepted by its primary.
+fn read_only(config: &Configuration) -> Option<HttpResponse> {
+    match (config.server.mode, &config.server.primary) {
+        (ServerMode::Follower, Some(primary)) => Some(HttpResponse::Forbidden().body(format!(
+            "This instance is a read-only follower, change the shortcuts on {primary}."
+        ))),
+        (ServerMode::Follower, None) => Some(
+            HttpResponse::Forbidden()
+                .body("This instance is a read-only follower, change the shortcuts on its primary."),
+        ),
+        (ServerMode::Primary, _) => None,
+    }
+}
+
+fn etag(revision: u64) -> (header::HeaderName, String) {
+    (header::ETAG, format!("\"{revision}\""))
+}
+
+/// Answers a 422 if another live shortcut already redirects under the slug or an alias of the entry,
+/// it would shadow the entry or be shadowed by it.
+fn taken(data: &dyn Storage, entry: &ShortcutEntry) -> Option<HttpResponse> {
+    std::iter::once(&entry.slug)
+        .chain(entry.aliases.iter())
+        .find_map(|name| {
+            let owner = data.resolve(name).filter(|owner| owner.slug != entry.slug)?;
+            Some(
+                HttpResponse::UnprocessableEntity()
+                    .body(format!("'{name}' is already taken by the shortcut '{}'.", owner.slug)),
+            )
+        })
+}
+
+/// Reads the expected revision from `If-Match`, or `If-None-Match: *` to only create.
+/// `If-Match: *` expects the shortcut read by `current` to exist, in any revision.
+/// Without either header the write is unconditional.
+fn precondition(
+    req: &HttpRequest,
+    current: impl FnOnce() -> Option<ShortcutEntry>,
+) -> Result<Option<u64>, HttpResponse> {
+    let headers = req.headers();
+    if let Some(value) = headers.get(header::IF_MATCH) {
+        let value = value.to_str().unwrap_or_default().trim();
+        if value == "*" {
+            return match current() {
+                Some(entry) => Ok(Some(entry.revision)),
+                None => Err(conflict(0)),
+            };
+        }
+        return value
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .parse()
+            .map(Some)
+            .map_err(|_| {
+                HttpResponse::BadRequest().body("Provide a revision ETag in the If-Match header.")
+            });
+    }
+    match headers.get(header::IF_NONE_MATCH) {
+        Some(value) if value.as_bytes() == b"*" => Ok(Some(0)),
+        _ => Ok(None),
+    }
+}
+
+fn conflict(current: u64) -> HttpResponse {
+    let mut res = HttpResponse::PreconditionFailed();
+    if current > 0 {
+        res.insert_header(etag(current));
+    }
+    res.body("The shortcut was changed in the meantime, reload it and try again.")
+}
+
 #[get("/health")]
-pub async fn health() -> impl Responder {
-    HttpResponse::Ok().finish()
+pub async fn health(status: Option<Data<Arc<ReplicationStatus>>>) -> impl Responder {
+    match status {
+        Some(status) => HttpResponse::Ok().json(HealthAnswer {
+            replication: status.report(),
+        }),
+        None => HttpResponse::Ok().finish(),
+    }
+}
+
+#[get("/gitops/drift")]
+pub async fn drift(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = handle_authorization(config.as_ref(), req.headers()).await {
+        return res;
+    }
+    let Some(gitops) = &config.gitops else {
+        return HttpResponse::NotFound().body("No seed file is reconciled.");
+    };
+    match gitops::drift(data.as_ref().as_ref(), gitops, &config.slugs) {
+        Ok(report) => HttpResponse::Ok().json(DriftReport {
+            path: gitops.path.clone(),
+            unmanaged: report.removed,
+            missing: report.added,
+            modified: report.changed,
+            rejected: report.rejected,
+            conflicts: report.conflicts,
+        }),
+        Err(e) => {
+            println!("{e}");
+            HttpResponse::InternalServerError().body("Please retry again in a few seconds.")
+        }
+    }
+}
+
+/// All entries including the trash for peers to replicate, authorized by the shared secret.
+#[get("/replication/entries")]
+pub async fn replication_entries(
+    data: Data<Arc<dyn Storage>>,
+    config: Data<Configuration>,
+    req: HttpRequest,
+) -> impl Responder {
+    if !replication::authorized(&config.replication, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/csv")
+        .body(data.to_csv())
 }
 
 #[get("/oauth2/code")]
@@ -1,4 +1,7 @@
-use serde::{Deserialize, Serialize};
+use crate::codec::{Format, Rejection};
+use crate::database::{QueryPolicy, Trust};
+use crate::storage::Strategy;
+use serde::{Deserialize, Deserializer, Serialize};
 
 // CRUD DTOs
 
@@ -9,15 +12,93 @@ pub struct GetShortcut {
 
 #[derive(Debug, Deserialize)]
 pub struct PutShortcut {
+    /// Generated if empty or missing
+    #[serde(default)]
     pub slug: String,
     pub url: String,
     pub approval: bool,
     pub since: u128,
     pub until: u128,
+    /// Further slugs redirecting like this one, replacing the current ones
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Redirect status, 0 or missing for the configured default
+    #[serde(default)]
+    pub redirect: u16,
+    /// Query string policy, the configured default if missing
+    #[serde(default)]
+    pub query: Option<QueryPolicy>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PutShortcutAnwser {
     pub slug: String,
+    pub revision: u64,
+    pub created_at: u128,
+    pub created_by: String,
+    pub updated_at: u128,
+    pub updated_by: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShortcutDetails {
+    pub slug: String,
+    pub url: String,
+    pub status: Trust,
+    pub since: u128,
+    pub until: u128,
+    pub revision: u64,
+    pub created_at: u128,
+    pub created_by: String,
+    pub updated_at: u128,
+    pub updated_by: String,
+    pub aliases: Vec<String>,
+    /// Slug a renamed shortcut forwards to, empty for regular shortcuts
+    pub forward_to: String,
+    /// Redirect status, 0 for the configured default
+    pub redirect: u16,
+    /// Query string policy, `null` for the configured default
+    pub query: Option<QueryPolicy>,
+}
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    /// Only the revision in effect at this UNIX time in milliseconds
+    #[serde(default, deserialize_with = "query_millis")]
+    pub at: Option<u128>,
+}
+
+/// Query strings cannot be deserialized into `u128`, so the time is parsed from its text.
+fn query_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u128>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|at| at.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryItem {
+    pub revision: u64,
+    pub url: String,
+    pub status: Trust,
+    pub since: u128,
+    pub until: u128,
+    pub updated_at: u128,
+    pub updated_by: String,
+    pub deleted_at: u128,
+    pub deleted_by: String,
+    pub aliases: Vec<String>,
+    pub forward_to: String,
+    pub redirect: u16,
+    pub query: Option<QueryPolicy>,
+}
+#[derive(Debug, Deserialize)]
+pub struct RollbackShortcut {
+    /// Revision to roll back to, takes precedence over `at`
+    pub revision: Option<u64>,
+    /// Rolls back to the revision in effect at this UNIX time in milliseconds
+    pub at: Option<u128>,
+}
+#[derive(Debug, Deserialize)]
+pub struct RenameShortcut {
+    pub to: String,
+    /// UNIX time in milliseconds the old slug stops forwarding, forever if missing
+    pub forward_until: Option<u128>,
 }
 #[derive(Debug, Deserialize)]
 pub struct DeleteShortcut {
@@ -30,15 +111,114 @@ pub struct DeleteShortcut {
 pub struct ShortcutItem {
     pub slug: String,
     pub url: String,
-    pub status: String,
+    pub status: Trust,
     pub now: u128,
     pub since: u128,
     pub until: u128,
+    pub revision: u64,
+    pub created_at: u128,
+    pub created_by: String,
+    pub updated_at: u128,
+    pub updated_by: String,
+    pub aliases: Vec<String>,
+    pub forward_to: String,
+    pub redirect: u16,
+    pub query: Option<QueryPolicy>,
+}
+
+#[derive(Serialize)]
+pub struct TrashItem {
+    pub slug: String,
+    pub url: String,
+    pub deleted_at: u128,
+    pub deleted_by: String,
+    pub purge_at: u128,
 }
 
 #[derive(Serialize)]
 pub struct ShortcutList {
     pub items: Vec<ShortcutItem>,
+    pub trash: Vec<TrashItem>,
+}
+
+// STORE
+
+#[derive(Deserialize)]
+pub struct StoreQuery {
+    pub format: Option<Format>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub dry_run: bool,
+    pub format: Option<Format>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportAnswer {
+    pub imported: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportRejections {
+    pub rejected: Vec<Rejection>,
+    /// Names more than one shortcut would redirect under after the import
+    pub conflicts: Vec<String>,
+}
+
+// SNAPSHOTS
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetSnapshot {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotItem {
+    pub name: String,
+    pub size: u64,
+    pub modified: u128,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotList {
+    pub items: Vec<SnapshotItem>,
+}
+
+// GITOPS DTOs
+
+/// Differences between the shortcuts and the seed file, by slug.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub path: String,
+    /// Live shortcuts missing in the seed file, like ones created at runtime
+    pub unmanaged: Vec<String>,
+    /// Shortcuts of the seed file that are not live
+    pub missing: Vec<String>,
+    /// Shortcuts that point elsewhere than the seed file says
+    pub modified: Vec<String>,
+    pub rejected: Vec<Rejection>,
+    /// Names more than one shortcut would redirect under after the next reconciliation
+    pub conflicts: Vec<String>,
+}
+
+// REPLICATION DTOs
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthAnswer {
+    pub replication: Vec<PeerLag>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerLag {
+    pub peer: String,
+    /// Milliseconds since the UNIX epoch of the last successful pull
+    pub synced_at: Option<u128>,
+    /// Milliseconds since the last successful pull
+    pub lag: Option<u128>,
+    pub error: Option<String>,
 }
 
 // GATE CONTEXT
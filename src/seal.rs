@@ -0,0 +1,222 @@
+use crate::configuration::Encryption;
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::engine::{general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+
+/// First line of sealed files, followed by the algorithm and its parameter.
+/// Files without it were written before sealing and are read as they are.
+const MARKER: &str = "# zorka sealed v1 ";
+/// First line of an encrypted journal written before records were bound to their position.
+const LEGACY_JOURNAL_MARKER: &str = "# zorka sealed v1 aes-256-gcm journal\n";
+/// First line of an encrypted journal, followed by the random id of the file.
+/// Every following line is one encrypted record.
+const JOURNAL_MARKER: &str = "# zorka sealed v2 aes-256-gcm journal ";
+const NONCE_LENGTH: usize = 12;
+
+/// Cipher for the configured key, fails if the key is not a base64 encoded 256 bit key.
+pub fn cipher(encryption: &Encryption) -> Result<Aes256Gcm, String> {
+    let key = match encryption {
+        Encryption::Key(key) => key.clone(),
+        Encryption::KeyFile(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read the key file: {e}"))?
+            .trim()
+            .to_string(),
+    };
+    let key = general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| format!("the key is not base64: {e}"))?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| "the key must be 32 bytes long".into())
+}
+
+/// Encrypts the content with the key or, without encryption, prefixes it with its SHA-256 checksum.
+pub fn seal(content: &str, encryption: Option<&Encryption>) -> String {
+    match encryption {
+        Some(encryption) => {
+            let cipher = cipher(encryption).expect("invalid backup key");
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let header = format!(
+                "{MARKER}aes-256-gcm {}",
+                general_purpose::STANDARD.encode(nonce)
+            );
+            // The header is authenticated as well, so the nonce cannot be swapped
+            let ciphertext = cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: content.as_bytes(),
+                        aad: header.as_bytes(),
+                    },
+                )
+                .expect("could not encrypt");
+            format!("{header}\n{}\n", general_purpose::STANDARD.encode(ciphertext))
+        }
+        None => format!("{MARKER}sha256 {}\n{content}", checksum(content)),
+    }
+}
+
+/// Verifies and decrypts a sealed file. Unsealed content is returned as is.
+/// With a key only encrypted files are accepted, unless `unencrypted` allows files with a checksum or without a seal.
+pub fn unseal(
+    content: &str,
+    encryption: Option<&Encryption>,
+    unencrypted: bool,
+) -> Result<String, String> {
+    let encrypted = content.starts_with(&format!("{MARKER}aes-256-gcm "));
+    if encryption.is_some() && !encrypted && !unencrypted {
+        return Err("the file is not encrypted with the configured key".into());
+    }
+    let Some(rest) = content.strip_prefix(MARKER) else {
+        return Ok(content.to_string());
+    };
+    let (header, body) = rest.split_once('\n').unwrap_or((rest, ""));
+    match header.split_once(' ') {
+        Some(("sha256", expected)) => {
+            if checksum(body) == expected.trim() {
+                Ok(body.to_string())
+            } else {
+                Err("the checksum does not match, the file is corrupted or was tampered with".into())
+            }
+        }
+        Some(("aes-256-gcm", nonce)) => {
+            let Some(encryption) = encryption else {
+                return Err("the file is encrypted but no key is configured".into());
+            };
+            let nonce = general_purpose::STANDARD
+                .decode(nonce.trim())
+                .ok()
+                .filter(|nonce| nonce.len() == NONCE_LENGTH)
+                .ok_or("the nonce is invalid")?;
+            let ciphertext = general_purpose::STANDARD
+                .decode(body.trim())
+                .map_err(|_| "the ciphertext is not base64")?;
+            let plaintext = cipher(encryption)?
+                .decrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: format!("{MARKER}{header}").as_bytes(),
+                    },
+                )
+                .map_err(|_| "the file was tampered with or the key is wrong")?;
+            String::from_utf8(plaintext).map_err(|_| "the file is not utf-8".into())
+        }
+        _ => Err(format!("unsupported seal '{header}'")),
+    }
+}
+
+/// Seals the records of one journal file. Every record is bound to the random id of the file and
+/// its position in it, so records cannot be dropped, reordered, duplicated or moved between files unnoticed.
+pub struct JournalSeal {
+    cipher: Option<Aes256Gcm>,
+    id: String,
+    next: u64,
+}
+
+impl JournalSeal {
+    /// Seal of a new journal file, records are kept as they are without encryption.
+    pub fn new(encryption: Option<&Encryption>) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        Self {
+            cipher: encryption.map(|encryption| cipher(encryption).expect("invalid backup key")),
+            id: general_purpose::URL_SAFE_NO_PAD.encode(id),
+            next: 0,
+        }
+    }
+
+    /// First line of the journal, empty without encryption.
+    pub fn header(&self) -> String {
+        match self.cipher {
+            Some(_) => format!("{JOURNAL_MARKER}{}\n", self.id),
+            None => String::new(),
+        }
+    }
+
+    /// Encrypts the next record into a single line.
+    pub fn seal(&mut self, record: &str) -> String {
+        let Some(cipher) = &self.cipher else {
+            return record.to_string();
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = record_aad(&self.id, self.next);
+        self.next += 1;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: record.as_bytes(),
+                        aad: aad.as_bytes(),
+                    },
+                )
+                .expect("could not encrypt"),
+        );
+        format!("{}\n", general_purpose::STANDARD.encode(sealed))
+    }
+}
+
+/// Authenticated position of a record in its journal.
+fn record_aad(id: &str, index: u64) -> String {
+    format!("{JOURNAL_MARKER}{id} {index}")
+}
+
+/// Decrypts an encrypted journal. Records after the first one failing to decrypt, like a line torn
+/// by a crash or one dropped, reordered or copied from another file, are skipped.
+/// With a key unencrypted journals and those written before records were bound to their position
+/// are only replayed if `unencrypted` allows them, without a key they are returned as they are.
+pub fn unseal_journal(content: &str, encryption: Option<&Encryption>, unencrypted: bool) -> String {
+    let legacy = content.strip_prefix(LEGACY_JOURNAL_MARKER);
+    let bound = content.strip_prefix(JOURNAL_MARKER).and_then(|rest| rest.split_once('\n'));
+    if legacy.is_none() && bound.is_none() {
+        if encryption.is_some() && !unencrypted && !content.is_empty() {
+            println!("Skipping the journal: it is not encrypted, set backups.migrate_unencrypted to replay it once");
+            return String::new();
+        }
+        return content.to_string();
+    }
+    let Some(cipher) = encryption.and_then(|encryption| cipher(encryption).ok()) else {
+        println!("Skipping the journal: it is encrypted but no key is configured");
+        return String::new();
+    };
+    if legacy.is_some() && !unencrypted {
+        println!("Skipping the journal: its records are not bound to it, set backups.migrate_unencrypted to replay it once");
+        return String::new();
+    }
+    let records = legacy.or(bound.map(|(_, records)| records)).unwrap_or_default();
+    let mut journal = String::new();
+    for (index, line) in records.lines().enumerate() {
+        let aad = bound.map(|(id, _)| record_aad(id.trim(), index as u64));
+        let record = general_purpose::STANDARD
+            .decode(line)
+            .ok()
+            .filter(|sealed| sealed.len() > NONCE_LENGTH)
+            .and_then(|sealed| {
+                let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: aad.as_deref().unwrap_or_default().as_bytes(),
+                };
+                cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+            })
+            .and_then(|record| String::from_utf8(record).ok());
+        match record {
+            Some(record) => journal.push_str(&record),
+            None => {
+                println!("Skipping the journal from line {}: the record could not be decrypted", index + 2);
+                break;
+            }
+        }
+    }
+    journal
+}
+
+fn checksum(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
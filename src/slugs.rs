@@ -0,0 +1,147 @@
+use crate::configuration::{Normalization, SlugGenerator, Slugs};
+use crate::database::ShortcutEntry;
+use crate::storage::Storage;
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters easily mistaken for each other when a link is read out or typed from print.
+const AMBIGUOUS: &str = "0Oo1lI";
+
+/// Random slug of the configured length, drawn uniformly from the configured alphabet.
+pub fn generate(config: &SlugGenerator) -> String {
+    let alphabet = alphabet(config);
+    // Draws above the largest multiple of the alphabet size are redrawn to avoid a modulo bias,
+    // a u32 per character keeps that multiple above zero for alphabets of any size
+    let size = alphabet.len() as u64;
+    let limit = (1u64 << 32) - (1u64 << 32) % size;
+    let mut slug = String::with_capacity(config.length);
+    let mut bytes = [0u8; 4];
+    while slug.chars().count() < config.length {
+        getrandom::getrandom(&mut bytes).expect("the operating system random source is unavailable");
+        let draw = u32::from_le_bytes(bytes) as u64;
+        if draw < limit {
+            slug.push(alphabet[(draw % size) as usize]);
+        }
+    }
+    slug
+}
+
+/// Reason the slug cannot be created, `None` if it is neither reserved nor contains a blocked word.
+pub fn restriction(config: &Slugs, slug: &str) -> Option<String> {
+    let lowercase = slug.to_lowercase();
+    if config
+        .reserved
+        .iter()
+        .any(|reserved| reserved.to_lowercase() == lowercase)
+        || config.reserved_patterns.iter().any(|pattern| {
+            Regex::new(&format!("^(?:{pattern})$")).is_ok_and(|regex| regex.is_match(slug))
+        })
+    {
+        return Some(format!("The slug '{slug}' is reserved."));
+    }
+    if config
+        .blocked
+        .iter()
+        .any(|word| lowercase.contains(&word.to_lowercase()))
+    {
+        return Some(format!("The slug '{slug}' contains a blocked word."));
+    }
+    None
+}
+
+/// Unique characters of the alphabet, without the ambiguous ones if configured.
+pub fn alphabet(config: &SlugGenerator) -> Vec<char> {
+    let mut alphabet: Vec<char> = vec![];
+    for character in config.alphabet.chars() {
+        let ambiguous = config.avoid_ambiguous && AMBIGUOUS.contains(character);
+        if !ambiguous && !alphabet.contains(&character) {
+            alphabet.push(character);
+        }
+    }
+    alphabet
+}
+
+/// Confusable characters of other scripts and the Latin characters they pass for.
+/// ASCII characters are never mapped, `1` and `l` or `0` and `o` remain distinct names.
+const CONFUSABLES: &[(char, char)] = &[
+    ('а', 'a'), ('ɑ', 'a'), ('α', 'a'), ('Ь', 'b'), ('Ƅ', 'b'), ('с', 'c'), ('ϲ', 'c'), ('ԁ', 'd'),
+    ('е', 'e'), ('ё', 'ë'), ('ɡ', 'g'), ('һ', 'h'), ('і', 'i'), ('ι', 'i'), ('ї', 'ï'), ('ј', 'j'),
+    ('κ', 'k'), ('к', 'k'), ('ӏ', 'l'), ('м', 'm'), ('ո', 'n'), ('η', 'n'),
+    ('о', 'o'), ('ο', 'o'), ('σ', 'o'), ('р', 'p'), ('ρ', 'p'), ('ԛ', 'q'), ('г', 'r'),
+    ('ѕ', 's'), ('т', 't'), ('τ', 't'), ('υ', 'u'), ('ս', 'u'), ('ν', 'v'), ('ѵ', 'v'), ('ԝ', 'w'),
+    ('ω', 'w'), ('х', 'x'), ('χ', 'x'), ('у', 'y'), ('γ', 'y'), ('ᴢ', 'z'),
+];
+
+/// The slug as it is stored and looked up under the policy.
+pub fn normalize(policy: &Normalization, slug: &str) -> String {
+    let mut slug = if policy.trim { slug.trim() } else { slug }.to_string();
+    if policy.fold_case {
+        slug = slug.to_lowercase();
+    }
+    // Lowercasing can decompose characters, so composing comes last
+    if policy.nfc {
+        slug = slug.nfc().collect();
+    }
+    slug
+}
+
+/// Normalizes every name of the entry: the slug, its aliases and the slug it forwards to.
+pub fn normalize_entry(policy: &Normalization, entry: &mut ShortcutEntry) {
+    entry.slug = normalize(policy, &entry.slug);
+    for alias in entry.aliases.iter_mut() {
+        *alias = normalize(policy, alias);
+    }
+    if !entry.forward_to.is_empty() {
+        entry.forward_to = normalize(policy, &entry.forward_to);
+    }
+}
+
+/// Import check of a seed file uploaded or reconciled on behalf of the author: normalizes every name
+/// of the entry and rejects it if one of them is restricted, unless the author may override that.
+pub fn check_import(config: &Slugs, author: &str, entry: &mut ShortcutEntry) -> Result<(), String> {
+    normalize_entry(&config.normalize, entry);
+    if config.override_by.iter().any(|identity| identity == author) {
+        return Ok(());
+    }
+    match std::iter::once(&entry.slug)
+        .chain(entry.aliases.iter())
+        .find_map(|name| restriction(config, name))
+    {
+        Some(message) => Err(message),
+        None => Ok(()),
+    }
+}
+
+/// What the slug looks like, equal for slugs that are only told apart by confusable characters.
+pub fn skeleton(slug: &str) -> String {
+    slug.nfkc()
+        .flat_map(char::to_lowercase)
+        .map(|character| {
+            CONFUSABLES
+                .iter()
+                .find(|(confusable, _)| *confusable == character)
+                .map_or(character, |(_, prototype)| *prototype)
+        })
+        .collect()
+}
+
+/// Reason the name cannot be used if it looks like but is not a name of another live shortcut,
+/// `None` if confusables are allowed or there is no such shortcut. Tombstones forwarding to the
+/// shortcut with the slug do not count.
+pub fn lookalike(policy: &Normalization, data: &dyn Storage, slug: &str, name: &str) -> Option<String> {
+    if !policy.reject_confusables {
+        return None;
+    }
+    let skeleton_of_name = skeleton(name);
+    data.lookalikes(&skeleton_of_name)
+        .iter()
+        .filter(|entry| entry.slug != slug && !(entry.is_tombstone() && entry.forward_to == slug))
+        .find_map(|entry| {
+            std::iter::once(&entry.slug)
+                .chain(entry.aliases.iter())
+                .find(|other| *other != name && skeleton(other) == skeleton_of_name)
+                .map(|other| {
+                    format!("'{name}' looks like '{other}' of the shortcut '{}'.", entry.slug)
+                })
+        })
+}
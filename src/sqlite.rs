@@ -0,0 +1,424 @@
+use crate::{
+    configuration::Backups,
+    database::{restore_data, QueryPolicy, ShortcutEntry, Trust},
+    slugs,
+    storage::{prune_backups, write_snapshot, Storage, WriteError},
+};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Schema migrations, the database's `user_version` is the number of applied migrations.
+const MIGRATIONS: [&str; 12] = [
+    "CREATE TABLE IF NOT EXISTS shortcuts (
+        slug TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        status TEXT NOT NULL,
+        since TEXT NOT NULL,
+        until TEXT NOT NULL
+    );",
+    "CREATE TABLE shortcuts_typed (
+        slug TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        trust TEXT NOT NULL CHECK (trust IN ('trusted', 'untrusted')),
+        since INTEGER NOT NULL,
+        until INTEGER NOT NULL
+    );
+    INSERT INTO shortcuts_typed
+        SELECT slug, url, status, CAST(since AS INTEGER), CAST(until AS INTEGER) FROM shortcuts;
+    DROP TABLE shortcuts;
+    ALTER TABLE shortcuts_typed RENAME TO shortcuts;",
+    "ALTER TABLE shortcuts ADD COLUMN revision INTEGER NOT NULL DEFAULT 1;",
+    "ALTER TABLE shortcuts ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE shortcuts ADD COLUMN created_by TEXT NOT NULL DEFAULT '';
+    ALTER TABLE shortcuts ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE shortcuts ADD COLUMN updated_by TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE shortcuts ADD COLUMN deleted_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE shortcuts ADD COLUMN deleted_by TEXT NOT NULL DEFAULT '';",
+    "CREATE TABLE history (
+        slug TEXT NOT NULL,
+        url TEXT NOT NULL,
+        trust TEXT NOT NULL,
+        since INTEGER NOT NULL,
+        until INTEGER NOT NULL,
+        revision INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        created_by TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        updated_by TEXT NOT NULL,
+        deleted_at INTEGER NOT NULL,
+        deleted_by TEXT NOT NULL,
+        PRIMARY KEY (slug, revision)
+    );
+    INSERT INTO history SELECT slug, url, trust, since, until, revision, created_at, created_by, updated_at, updated_by, deleted_at, deleted_by FROM shortcuts;",
+    "ALTER TABLE shortcuts ADD COLUMN aliases TEXT NOT NULL DEFAULT '';
+    ALTER TABLE history ADD COLUMN aliases TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE shortcuts ADD COLUMN forward_to TEXT NOT NULL DEFAULT '';
+    ALTER TABLE history ADD COLUMN forward_to TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE shortcuts ADD COLUMN redirect INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE history ADD COLUMN redirect INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE shortcuts ADD COLUMN query TEXT NOT NULL DEFAULT '';
+    ALTER TABLE history ADD COLUMN query TEXT NOT NULL DEFAULT '';",
+    "CREATE TABLE names (
+        skeleton TEXT NOT NULL,
+        slug TEXT NOT NULL
+    );
+    CREATE INDEX names_skeleton ON names (skeleton);
+    CREATE INDEX names_slug ON names (slug);",
+    "ALTER TABLE names ADD COLUMN name TEXT NOT NULL DEFAULT '';
+    CREATE INDEX names_name ON names (name, slug);",
+];
+
+/// Columns of the `shortcuts` and `history` tables in the order `from_row` reads them.
+const COLUMNS: &str = "slug, url, trust, since, until, revision, created_at, created_by, updated_at, updated_by, deleted_at, deleted_by, aliases, forward_to, redirect, query";
+
+/// Embedded SQLite storage. Every mutation is its own transaction, so nothing is held in memory.
+/// The latest revisions up to the history limit are kept in the `history` table until the entry is deleted.
+pub struct SqliteStorage {
+    backups: Option<Backups>,
+    instance_id: String,
+    connection: Mutex<Connection>,
+    history_limit: usize,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database file. A new database is populated from
+    /// the backups directory (or the seed file), the same way the in-memory storage restores.
+    pub fn new(path: &str, backups: Option<&Backups>) -> Self {
+        let mut connection = Connection::open(path).expect("could not open the sqlite database");
+        connection
+            .execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;")
+            .expect("could not configure the sqlite database");
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .expect("could not read the sqlite schema version");
+        let transaction = connection
+            .transaction()
+            .expect("could not migrate the sqlite database");
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            transaction
+                .execute_batch(&format!("{migration} PRAGMA user_version = {};", index + 1))
+                .expect("could not migrate the sqlite database");
+        }
+        if version == 0 {
+            if let Some(dir) = backups {
+                for entry in restore_data(dir).values() {
+                    insert(&transaction, "shortcuts", entry).expect("could not import the backups");
+                    insert(&transaction, "history", entry).expect("could not import the backups");
+                }
+            }
+        }
+        // Skeletons depend on the confusables of the running version, so the index is rebuilt
+        index_all(&transaction).expect("could not index the slugs");
+        transaction
+            .commit()
+            .expect("could not migrate the sqlite database");
+
+        Self {
+            backups: backups.cloned(),
+            instance_id: Uuid::new_v4().to_string(),
+            connection: Mutex::new(connection),
+            history_limit: backups
+                .map_or(Backups::default().history_limit, |backups| backups.history_limit)
+                .max(1),
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn read(&self, slug: &str) -> Option<ShortcutEntry> {
+        let connection = self.connection.lock().ok()?;
+        match current(&connection, slug) {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("{e}");
+                None
+            }
+        }
+    }
+    fn read_all(&self) -> Vec<ShortcutEntry> {
+        let Ok(connection) = self.connection.lock() else {
+            return vec![];
+        };
+        let rows = connection
+            .prepare(&format!("SELECT {COLUMNS} FROM shortcuts"))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], from_row)?
+                    .collect::<Result<Vec<ShortcutEntry>, _>>()
+            });
+        match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                println!("{e}");
+                vec![]
+            }
+        }
+    }
+    fn upsert(
+        &self,
+        mut value: ShortcutEntry,
+        expected: Option<u64>,
+    ) -> Result<ShortcutEntry, WriteError> {
+        let mut connection = self.connection.lock().map_err(|_| WriteError::Failed)?;
+        let result = connection.transaction().and_then(|transaction| {
+            let current = current(&transaction, &value.slug)?;
+            let revision = current.as_ref().map_or(0, |entry| entry.revision);
+            if expected.is_some_and(|expected| expected != revision) {
+                return Ok(Err(WriteError::Conflict(revision)));
+            }
+            // A new shortcut replacing a trashed one starts its own history
+            if let Some(current) = current.filter(|current| !current.is_trashed()) {
+                value.created_at = current.created_at;
+                value.created_by = current.created_by;
+            }
+            value.revision = revision + 1;
+            insert(&transaction, "shortcuts", &value)?;
+            insert(&transaction, "history", &value)?;
+            trim_history(&transaction, &value.slug, self.history_limit)?;
+            index(&transaction, &value)?;
+            transaction.commit()?;
+            Ok(Ok(value))
+        });
+        match result {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{e}");
+                Err(WriteError::Failed)
+            }
+        }
+    }
+    fn delete(&self, slug: &str, expected: Option<u64>) -> Result<bool, WriteError> {
+        let mut connection = self.connection.lock().map_err(|_| WriteError::Failed)?;
+        let result = connection.transaction().and_then(|transaction| {
+            let revision = current(&transaction, slug)?.map_or(0, |entry| entry.revision);
+            if expected.is_some_and(|expected| expected != revision) {
+                return Ok(Err(WriteError::Conflict(revision)));
+            }
+            let changed = transaction.execute("DELETE FROM shortcuts WHERE slug = ?1", [slug])?;
+            transaction.execute("DELETE FROM history WHERE slug = ?1", [slug])?;
+            transaction.execute("DELETE FROM names WHERE slug = ?1", [slug])?;
+            transaction.commit()?;
+            Ok(Ok(changed > 0))
+        });
+        match result {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{e}");
+                Err(WriteError::Failed)
+            }
+        }
+    }
+    fn resolve(&self, slug: &str) -> Option<ShortcutEntry> {
+        let connection = self.connection.lock().ok()?;
+        // Exact slugs are looked up by the primary key, aliases by the indexed names of live entries
+        let entry = current(&connection, slug)
+            .map(|entry| entry.filter(|entry| !entry.is_trashed()))
+            .and_then(|entry| match entry {
+                Some(entry) => Ok(Some(entry)),
+                None => connection
+                    .query_row(
+                        &format!(
+                            "SELECT {COLUMNS} FROM shortcuts WHERE slug =
+                            (SELECT slug FROM names WHERE name = ?1 ORDER BY slug LIMIT 1)"
+                        ),
+                        [slug],
+                        from_row,
+                    )
+                    .optional(),
+            });
+        match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("{e}");
+                None
+            }
+        }
+    }
+    fn lookalikes(&self, skeleton: &str) -> Vec<ShortcutEntry> {
+        let Ok(connection) = self.connection.lock() else {
+            return vec![];
+        };
+        let rows = connection
+            .prepare(&format!(
+                "SELECT {COLUMNS} FROM shortcuts WHERE slug IN
+                (SELECT slug FROM names WHERE skeleton = ?1)"
+            ))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([skeleton], from_row)?
+                    .collect::<Result<Vec<ShortcutEntry>, _>>()
+            });
+        match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                println!("{e}");
+                vec![]
+            }
+        }
+    }
+    fn merge(&self, value: ShortcutEntry) -> Result<bool, WriteError> {
+        let mut connection = self.connection.lock().map_err(|_| WriteError::Failed)?;
+        let result = connection.transaction().and_then(|transaction| {
+            if current(&transaction, &value.slug)?.is_some_and(|current| !value.supersedes(&current)) {
+                return Ok(false);
+            }
+            insert(&transaction, "shortcuts", &value)?;
+            insert(&transaction, "history", &value)?;
+            trim_history(&transaction, &value.slug, self.history_limit)?;
+            index(&transaction, &value)?;
+            transaction.commit()?;
+            Ok(true)
+        });
+        result.map_err(|e| {
+            println!("{e}");
+            WriteError::Failed
+        })
+    }
+    fn history(&self, slug: &str) -> Vec<ShortcutEntry> {
+        let Ok(connection) = self.connection.lock() else {
+            return vec![];
+        };
+        let rows = connection
+            .prepare(&format!(
+                "SELECT {COLUMNS} FROM history WHERE slug = ?1 ORDER BY revision"
+            ))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([slug], from_row)?
+                    .collect::<Result<Vec<ShortcutEntry>, _>>()
+            });
+        match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                println!("{e}");
+                vec![]
+            }
+        }
+    }
+    fn snapshot(&self) -> Option<String> {
+        let backups = self.backups.as_ref()?;
+        let name = write_snapshot(backups, &self.instance_id, &self.to_csv())?;
+        prune_backups(backups, &name);
+        Some(name)
+    }
+    fn backups(&self) -> Option<&Backups> {
+        self.backups.as_ref()
+    }
+}
+
+fn insert(connection: &Connection, table: &str, entry: &ShortcutEntry) -> rusqlite::Result<usize> {
+    connection.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {table} ({COLUMNS})
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)"
+        ),
+        params![
+            entry.slug,
+            entry.url,
+            entry.trust.to_string(),
+            to_integer(entry.since)?,
+            to_integer(entry.until)?,
+            entry.revision,
+            to_integer(entry.created_at)?,
+            entry.created_by,
+            to_integer(entry.updated_at)?,
+            entry.updated_by,
+            to_integer(entry.deleted_at)?,
+            entry.deleted_by,
+            entry.aliases.join(" "),
+            entry.forward_to,
+            entry.redirect,
+            entry.query.map(|query| query.to_string()).unwrap_or_default()
+        ],
+    )
+}
+
+/// Drops the revisions of the entry past the latest `limit` ones.
+fn trim_history(connection: &Connection, slug: &str, limit: usize) -> rusqlite::Result<usize> {
+    connection.execute(
+        "DELETE FROM history WHERE slug = ?1 AND revision NOT IN
+        (SELECT revision FROM history WHERE slug = ?1 ORDER BY revision DESC LIMIT ?2)",
+        params![slug, limit as i64],
+    )
+}
+
+/// Replaces the entry's names and their skeletons in the `names` table, trashed entries have none.
+fn index(connection: &Connection, entry: &ShortcutEntry) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM names WHERE slug = ?1", [&entry.slug])?;
+    if entry.is_trashed() {
+        return Ok(());
+    }
+    for name in entry.names() {
+        connection.execute(
+            "INSERT INTO names (skeleton, name, slug) VALUES (?1, ?2, ?3)",
+            [slugs::skeleton(name), name.clone(), entry.slug.clone()],
+        )?;
+    }
+    Ok(())
+}
+
+fn index_all(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM names", [])?;
+    let entries = connection
+        .prepare(&format!("SELECT {COLUMNS} FROM shortcuts WHERE deleted_at = 0"))?
+        .query_map([], from_row)?
+        .collect::<Result<Vec<ShortcutEntry>, _>>()?;
+    for entry in entries {
+        index(connection, &entry)?;
+    }
+    Ok(())
+}
+
+fn current(connection: &Connection, slug: &str) -> rusqlite::Result<Option<ShortcutEntry>> {
+    connection
+        .query_row(
+            &format!("SELECT {COLUMNS} FROM shortcuts WHERE slug = ?1"),
+            [slug],
+            from_row,
+        )
+        .optional()
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<ShortcutEntry> {
+    Ok(ShortcutEntry {
+        slug: row.get(0)?,
+        url: row.get(1)?,
+        trust: row
+            .get::<_, String>(2)?
+            .parse::<Trust>()
+            .map_err(|e: String| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?,
+        since: from_integer(row, 3)?,
+        until: from_integer(row, 4)?,
+        revision: row.get(5)?,
+        created_at: from_integer(row, 6)?,
+        created_by: row.get(7)?,
+        updated_at: from_integer(row, 8)?,
+        updated_by: row.get(9)?,
+        deleted_at: from_integer(row, 10)?,
+        deleted_by: row.get(11)?,
+        aliases: row
+            .get::<_, String>(12)?
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+        forward_to: row.get(13)?,
+        redirect: row.get(14)?,
+        query: match row.get::<_, String>(15)? {
+            query if query.is_empty() => None,
+            query => Some(query.parse::<QueryPolicy>().map_err(|e: String| {
+                rusqlite::Error::FromSqlConversionFailure(15, Type::Text, e.into())
+            })?),
+        },
+    })
+}
+
+fn to_integer(timestamp: u128) -> rusqlite::Result<i64> {
+    i64::try_from(timestamp).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+}
+
+fn from_integer(row: &Row, index: usize) -> rusqlite::Result<u128> {
+    u128::try_from(row.get::<_, i64>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Integer, e.into()))
+}
@@ -0,0 +1,589 @@
+use crate::{
+    codec::{self, Record, Rejection},
+    configuration::{Backups, Configuration, Normalization, StorageEngine},
+    database::{Database, ShortcutEntry},
+    seal, slugs,
+    sqlite::SqliteStorage,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, remove_file, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL: &str = "journal.log";
+pub const HISTORY: &str = "history.log";
+/// Identity the rewrites of `normalize` are attributed to.
+const NORMALIZER: &str = "normalize";
+
+pub struct Snapshot {
+    pub name: String,
+    pub size: u64,
+    pub modified: u128,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Removes every shortcut not present in the import
+    ReplaceAll,
+    /// Overwrites existing shortcuts with the imported ones
+    MergeOverwrite,
+    /// Only adds shortcuts that do not exist yet
+    MergeKeepExisting,
+}
+
+/// Outcome of an import by slug. Rejected records did not pass the validation.
+/// An import is only applied without rejected records and conflicts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub removed: Vec<String>,
+    pub rejected: Vec<Rejection>,
+    /// Names more than one shortcut would redirect under after the import
+    pub conflicts: Vec<String>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// Some records did not pass the validation or their names conflict, nothing was applied
+    Rejected(Box<ImportReport>),
+    /// The changes could not be stored, the ones applied before were reverted
+    Failed,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    /// The stored revision, 0 if the slug is free, did not match the expected one
+    Conflict(u64),
+    /// The write could not be persisted
+    Failed,
+}
+
+/// Persistence engine the routes operate on.
+pub trait Storage: Send + Sync {
+    fn read(&self, slug: &str) -> Option<ShortcutEntry>;
+    fn read_all(&self) -> Vec<ShortcutEntry>;
+    /// Stores the entry with the next revision and returns it as stored.
+    /// With an expected revision the write only happens if it matches the stored one, 0 expecting a free slug.
+    fn upsert(
+        &self,
+        value: ShortcutEntry,
+        expected: Option<u64>,
+    ) -> Result<ShortcutEntry, WriteError>;
+    /// Permanently deletes the entry, returns `false` if there was none.
+    /// With an expected revision the delete only happens if it matches the stored one.
+    fn delete(&self, slug: &str, expected: Option<u64>) -> Result<bool, WriteError>;
+    /// Live entry redirecting under the slug, its own or one it is an alias of.
+    /// Slugs win over aliases of other entries, of several aliases the one of the first slug wins.
+    fn resolve(&self, slug: &str) -> Option<ShortcutEntry> {
+        match self.read(slug).filter(|entry| !entry.is_trashed()) {
+            Some(entry) => Some(entry),
+            None => self
+                .read_all()
+                .into_iter()
+                .filter(|entry| !entry.is_trashed() && entry.answers(slug))
+                .min_by(|a, b| a.slug.cmp(&b.slug)),
+        }
+    }
+    /// Live entries with a slug or alias of the given skeleton, see `slugs::skeleton`.
+    fn lookalikes(&self, skeleton: &str) -> Vec<ShortcutEntry> {
+        self.read_all()
+            .into_iter()
+            .filter(|entry| !entry.is_trashed() && entry.names().any(|name| slugs::skeleton(name) == skeleton))
+            .collect()
+    }
+    /// Stores an entry replicated from a peer as it is, revision and metadata included,
+    /// if it supersedes the stored version. Returns whether it was stored.
+    fn merge(&self, value: ShortcutEntry) -> Result<bool, WriteError>;
+    /// Every stored revision of the entry, oldest first. Purged entries have no history.
+    fn history(&self, slug: &str) -> Vec<ShortcutEntry>;
+    /// Writes a csv snapshot of the current state into the backups directory.
+    /// Returns the name of the snapshot or `None` if the storage is ephemeral or the write failed.
+    fn snapshot(&self) -> Option<String>;
+    /// Backup settings, `None` if the storage is ephemeral.
+    fn backups(&self) -> Option<&Backups>;
+
+    /// Writes the final snapshot before shutdown. Returns `false` if the state could not be persisted.
+    fn flush(&self) -> bool {
+        if self.backups().is_none() {
+            return true;
+        }
+        match self.snapshot() {
+            Some(name) => {
+                println!("Wrote the final snapshot {name}");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves a live entry to the trash, returns `false` if there was none.
+    fn trash(&self, slug: &str, expected: Option<u64>, author: &str) -> Result<bool, WriteError> {
+        self.update(slug, expected, Some(false), &|entry| entry.trash(author))
+            .map(|entry| entry.is_some())
+    }
+
+    /// Restores an entry from the trash, returns it as stored or `None` if it is not in the trash.
+    fn untrash(
+        &self,
+        slug: &str,
+        expected: Option<u64>,
+        author: &str,
+    ) -> Result<Option<ShortcutEntry>, WriteError> {
+        self.update(slug, expected, Some(true), &|entry| entry.untrash(author))
+    }
+
+    /// Revision that was in effect at the given UNIX time in milliseconds.
+    /// Revisions without a known change time are considered to be in effect from the beginning.
+    fn revision_at(&self, slug: &str, at: u128) -> Option<ShortcutEntry> {
+        self.history(slug)
+            .into_iter()
+            .take_while(|entry| entry.updated_at <= at)
+            .last()
+    }
+
+    /// Points the entry to the target of a previous revision again, restoring it from the trash if needed.
+    /// The rollback is stored as a new revision. Returns `None` if the entry or revision is unknown.
+    fn rollback(
+        &self,
+        slug: &str,
+        revision: u64,
+        expected: Option<u64>,
+        author: &str,
+    ) -> Result<Option<ShortcutEntry>, WriteError> {
+        let Some(target) = self
+            .history(slug)
+            .into_iter()
+            .find(|entry| entry.revision == revision)
+        else {
+            return Ok(None);
+        };
+        self.update(slug, expected, None, &|entry| {
+            entry.url = target.url.clone();
+            entry.trust = target.trust;
+            entry.since = target.since;
+            entry.until = target.until;
+            entry.forward_to = target.forward_to.clone();
+            entry.redirect = target.redirect;
+            entry.query = target.query;
+            entry.untrash(author);
+        })
+    }
+
+    /// Moves the live entry to the free slug `to` and replaces it with a tombstone forwarding there
+    /// until the given UNIX time in milliseconds. A trashed entry under `to` is replaced, like a new
+    /// shortcut replaces it. Returns the moved entry as stored or `None` if there is no live entry.
+    /// If the old slug changes in the meantime the move is reverted.
+    fn rename(
+        &self,
+        slug: &str,
+        to: &str,
+        forward_until: u128,
+        expected: Option<u64>,
+        author: &str,
+    ) -> Result<Option<ShortcutEntry>, WriteError> {
+        let Some(current) = self
+            .read(slug)
+            .filter(|entry| !entry.is_trashed() && !entry.is_tombstone())
+        else {
+            return Ok(None);
+        };
+        if expected.is_some_and(|expected| expected != current.revision) {
+            return Err(WriteError::Conflict(current.revision));
+        }
+        let mut moved = current.clone();
+        moved.slug = to.into();
+        moved.aliases.retain(|alias| alias != to);
+        moved.stamp(author);
+        // A trashed entry or the tombstone of an earlier rename away from `to` is replaced
+        let replaced = self
+            .read(to)
+            .filter(|entry| entry.is_trashed() || (entry.is_tombstone() && entry.forward_to == slug));
+        let moved = self.upsert(moved, Some(replaced.as_ref().map_or(0, |entry| entry.revision)))?;
+
+        let mut tombstone = ShortcutEntry::new(
+            slug.into(),
+            current.url.clone(),
+            current.trust,
+            0,
+            forward_until,
+        );
+        tombstone.forward_to = to.into();
+        tombstone.stamp(author);
+        if let Err(error) = self.upsert(tombstone, Some(current.revision)) {
+            let _ = match replaced {
+                Some(replaced) => self.upsert(replaced, Some(moved.revision)).map(|_| true),
+                None => self.delete(to, Some(moved.revision)),
+            };
+            return Err(error);
+        }
+        Ok(Some(moved))
+    }
+
+    /// Applies the change to the entry if it is live or trashed as requested, `None` accepting both.
+    /// Without an expected revision the entry is re-read until no other write happened in between.
+    fn update(
+        &self,
+        slug: &str,
+        expected: Option<u64>,
+        trashed: Option<bool>,
+        change: &dyn Fn(&mut ShortcutEntry),
+    ) -> Result<Option<ShortcutEntry>, WriteError> {
+        loop {
+            let Some(mut entry) = self.read(slug) else {
+                return match expected {
+                    Some(expected) if expected != 0 => Err(WriteError::Conflict(0)),
+                    _ => Ok(None),
+                };
+            };
+            if trashed.is_some_and(|trashed| trashed != entry.is_trashed()) {
+                return Ok(None);
+            }
+            let revision = entry.revision;
+            change(&mut entry);
+            match self.upsert(entry, Some(expected.unwrap_or(revision))) {
+                Err(WriteError::Conflict(_)) if expected.is_none() => continue,
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    /// Rewrites the entries restored from seed files, backups or peers that are not normalized under the policy.
+    /// A changed slug is moved like a rename, the old slug keeps its history as a tombstone forwarding to the
+    /// new one, which supersedes it on peers. Entries whose normalized slug is already taken are left as they are.
+    /// Returns the rewritten slugs.
+    fn normalize(&self, policy: &Normalization) -> Vec<String> {
+        let mut rewritten = vec![];
+        for entry in self.read_all() {
+            let mut normalized = entry.clone();
+            slugs::normalize_entry(policy, &mut normalized);
+            // Tombstones left by an earlier rewrite
+            if normalized == entry || entry.is_tombstone() && entry.forward_to == normalized.slug {
+                continue;
+            }
+            normalized.stamp(NORMALIZER);
+            if normalized.slug == entry.slug {
+                if self.upsert(normalized, Some(entry.revision)).is_ok() {
+                    rewritten.push(entry.slug);
+                }
+                continue;
+            }
+            let moved = match normalized.validate() {
+                Ok(_) => self.upsert(normalized, Some(0)),
+                Err(_) => Err(WriteError::Failed),
+            };
+            let Ok(moved) = moved else {
+                println!("Could not normalize the slug '{}', it is taken or invalid", entry.slug);
+                continue;
+            };
+            let forward_until = if entry.is_tombstone() { entry.until } else { i64::MAX as u128 };
+            let mut tombstone =
+                ShortcutEntry::new(entry.slug.clone(), entry.url.clone(), entry.trust, 0, forward_until);
+            tombstone.forward_to = moved.slug.clone();
+            tombstone.stamp(NORMALIZER);
+            if self.upsert(tombstone, Some(entry.revision)).is_err() {
+                let _ = self.delete(&moved.slug, Some(moved.revision));
+                println!("Could not normalize the slug '{}', it changed in the meantime", entry.slug);
+                continue;
+            }
+            rewritten.push(entry.slug);
+        }
+        rewritten
+    }
+
+    /// Permanently deletes the entries trashed before the given UNIX time in milliseconds.
+    /// Returns the purged slugs.
+    fn purge(&self, before: u128) -> Vec<String> {
+        self.read_all()
+            .into_iter()
+            .filter(|entry| entry.is_trashed() && entry.deleted_at < before)
+            // Skips entries restored in the meantime
+            .filter(|entry| self.delete(&entry.slug, Some(entry.revision)) == Ok(true))
+            .map(|entry| entry.slug)
+            .collect()
+    }
+
+    fn to_csv(&self) -> String {
+        codec::to_csv(&self.read_all())
+    }
+
+    fn snapshots(&self) -> Vec<Snapshot> {
+        let Some(backups) = self.backups() else {
+            return vec![];
+        };
+        backup_files(Path::new(&backups.directory))
+            .iter()
+            .filter_map(|path| {
+                let meta = path.metadata().ok()?;
+                Some(Snapshot {
+                    name: path.file_name()?.to_string_lossy().to_string(),
+                    size: meta.len(),
+                    modified: meta
+                        .modified()
+                        .ok()?
+                        .duration_since(UNIX_EPOCH)
+                        .ok()?
+                        .as_millis(),
+                })
+            })
+            .collect()
+    }
+
+    /// Applies parsed records with the given strategy, attributing the changes to the author.
+    /// Shortcuts removed by `replace-all` are moved to the trash.
+    /// A dry run only reports what would change. Nothing is applied if any record was rejected,
+    /// and a failed write reverts the changes applied before it.
+    fn import(
+        &self,
+        records: Vec<Result<Record, Rejection>>,
+        strategy: Strategy,
+        dry_run: bool,
+        author: &str,
+    ) -> Result<ImportReport, ImportError> {
+        let existing: HashMap<String, ShortcutEntry> = self
+            .read_all()
+            .into_iter()
+            .filter(|entry| !entry.is_trashed())
+            .map(|entry| (entry.slug.clone(), entry))
+            .collect();
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+        // Later records win over earlier ones with the same slug, like in seed files
+        let mut imported: HashMap<String, ShortcutEntry> = HashMap::new();
+        for record in records {
+            match record {
+                Ok(Record::Upsert(entry)) => {
+                    imported.insert(entry.slug.clone(), entry);
+                }
+                Ok(Record::Delete(_)) => {}
+                Err(rejection) => report.rejected.push(rejection),
+            }
+        }
+
+        let mut writes = vec![];
+        for (slug, entry) in imported.iter() {
+            match existing.get(slug) {
+                None => {
+                    report.added.push(slug.clone());
+                    writes.push(entry);
+                }
+                Some(current) if current.same_target(entry) => report.unchanged.push(slug.clone()),
+                Some(_) => match strategy {
+                    Strategy::MergeKeepExisting => report.unchanged.push(slug.clone()),
+                    Strategy::ReplaceAll | Strategy::MergeOverwrite => {
+                        report.changed.push(slug.clone());
+                        writes.push(entry);
+                    }
+                },
+            }
+        }
+        if let Strategy::ReplaceAll = strategy {
+            report.removed = existing
+                .keys()
+                .filter(|slug| !imported.contains_key(*slug))
+                .cloned()
+                .collect();
+        }
+        let mut live = existing.clone();
+        for slug in &report.removed {
+            live.remove(slug);
+        }
+        for entry in &writes {
+            live.insert(entry.slug.clone(), (*entry).clone());
+        }
+        report.conflicts = conflicts(live.values());
+
+        for list in [
+            &mut report.added,
+            &mut report.changed,
+            &mut report.unchanged,
+            &mut report.removed,
+        ] {
+            list.sort();
+        }
+        if dry_run {
+            return Ok(report);
+        }
+        if !report.rejected.is_empty() || !report.conflicts.is_empty() {
+            return Err(ImportError::Rejected(Box::new(report)));
+        }
+
+        // Every write remembers the version it replaced, so a failure can be reverted
+        let mut applied: Vec<(ShortcutEntry, Option<ShortcutEntry>)> = vec![];
+        let mut failed = false;
+        for entry in writes {
+            let previous = self.read(&entry.slug);
+            let expected = previous.as_ref().map_or(0, |entry| entry.revision);
+            let mut entry = entry.clone();
+            entry.stamp(author);
+            match self.upsert(entry, Some(expected)) {
+                Ok(stored) => applied.push((stored, previous)),
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        for slug in &report.removed {
+            if failed {
+                break;
+            }
+            let previous = self.read(slug);
+            let expected = previous.as_ref().map(|entry| entry.revision);
+            match self.update(slug, expected, Some(false), &|entry| entry.trash(author)) {
+                Ok(Some(stored)) => applied.push((stored, previous)),
+                _ => failed = true,
+            }
+        }
+        if !failed {
+            return Ok(report);
+        }
+        for (stored, previous) in applied.into_iter().rev() {
+            let reverted = match previous {
+                Some(previous) => self.upsert(previous, Some(stored.revision)).map(|_| true),
+                None => self.delete(&stored.slug, Some(stored.revision)),
+            };
+            if reverted.is_err() {
+                println!("Could not revert the import of '{}'", stored.slug);
+            }
+        }
+        Err(ImportError::Failed)
+    }
+
+    /// Reads a snapshot by name. Only names returned by `snapshots` are accepted.
+    fn read_snapshot(&self, name: &str) -> Option<String> {
+        let backups = self.backups()?;
+        let path = backup_files(Path::new(&backups.directory))
+            .into_iter()
+            .find(|path| path.file_name().is_some_and(|file| file == name))?;
+        fs::read_to_string(path).ok()
+    }
+}
+
+/// Names several of the live entries redirect under, each with the shortcuts claiming it.
+/// Slugs and aliases share one namespace, so a name may only be taken once.
+pub fn conflicts<'a>(live: impl IntoIterator<Item = &'a ShortcutEntry>) -> Vec<String> {
+    let mut owners: HashMap<&str, Vec<&str>> = HashMap::new();
+    for entry in live {
+        for name in std::iter::once(&entry.slug).chain(entry.aliases.iter()) {
+            owners.entry(name).or_default().push(&entry.slug);
+        }
+    }
+    let mut conflicts: Vec<String> = owners
+        .into_iter()
+        .filter_map(|(name, mut slugs)| {
+            slugs.sort();
+            slugs.dedup();
+            (slugs.len() > 1).then(|| {
+                format!("'{name}' is claimed by the shortcuts '{}'.", slugs.join("', '"))
+            })
+        })
+        .collect();
+    conflicts.sort();
+    conflicts
+}
+
+/// Opens the storage engine selected in the configuration.
+/// Restored slugs that do not match the configured policy are normalized, so they stay reachable.
+pub fn open(config: &Configuration) -> Arc<dyn Storage> {
+    let storage: Arc<dyn Storage> = match &config.storage {
+        StorageEngine::Memory => Arc::new(Database::new(Some(&config.backups))),
+        StorageEngine::Sqlite { path } => {
+            Arc::new(SqliteStorage::new(path, Some(&config.backups)))
+        }
+    };
+    let rewritten = storage.normalize(&config.slugs.normalize);
+    if !rewritten.is_empty() {
+        println!("Normalized {} slugs: {}", rewritten.len(), rewritten.join(", "));
+    }
+    storage
+}
+
+/// Atomically writes a new timestamped backup into the directory by syncing a temporary file and renaming it.
+/// The backup is sealed, encrypted if configured and otherwise with a checksum.
+pub fn write_snapshot(backups: &Backups, instance_id: &str, csv: &str) -> Option<String> {
+    let dir = Path::new(&backups.directory);
+    match fs::create_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => println!("{e}"),
+        _ => {}
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis();
+    let name = format!("{now}-{instance_id}.csv");
+    let snapshot = dir.join(&name);
+    let temporary = dir.join(format!("{name}.tmp"));
+    match File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(seal::seal(csv, backups.encryption.as_ref()).as_bytes())
+                .and_then(|_| file.sync_all())
+        })
+        .and_then(|_| fs::rename(&temporary, &snapshot))
+    {
+        Ok(_) => Some(name),
+        Err(e) => {
+            println!("Could not write the snapshot {snapshot:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Removes the backups exceeding the retention count or age. The backup named `keep` is never removed.
+pub fn prune_backups(backups: &Backups, keep: &str) {
+    let max_age = backups.retention_age.map(Duration::from_secs);
+    let retained = backups.retention_count.max(1);
+    for (index, path) in backup_files(Path::new(&backups.directory))
+        .iter()
+        .enumerate()
+    {
+        if path.file_name().is_some_and(|name| name == keep) {
+            continue;
+        }
+        let expired = max_age.is_some_and(|max_age| {
+            path.metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > max_age)
+        });
+        if index >= retained || expired {
+            if let Err(e) = remove_file(path) {
+                println!("{e}");
+            }
+        }
+    }
+}
+
+/// Lists the csv backups in the directory, newest first.
+pub fn backup_files(dir: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            let mut paths = vec![];
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if let Ok(meta) = entry.metadata() {
+                    if meta.is_file() && path.extension().is_some_and(|ext| ext == "csv") {
+                        paths.push((meta.modified().unwrap_or(UNIX_EPOCH), path));
+                    }
+                }
+            }
+            paths.sort_by(|a, b| b.cmp(a));
+            paths.into_iter().map(|(_, path)| path).collect()
+        }
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                println!("{e}");
+            }
+            vec![]
+        }
+    }
+}